@@ -0,0 +1,596 @@
+use anyhow::{bail, Context, Result};
+use evremap::deviceinfo::DeviceInfo;
+use evremap::mapping::*;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+struct Diagnostic {
+    severity: Severity,
+    message: String,
+}
+
+/// Identifies a mapping by its position in the config file,
+/// for example `remap[3]`
+pub fn rule_name(mappings: &[Mapping], idx: usize) -> String {
+    fn kind(map: &Mapping) -> &'static str {
+        match map {
+            Mapping::DualRole { .. } => "dual_role",
+            Mapping::Remap { .. } => "remap",
+            Mapping::Disable { .. } => "disable",
+            _ => "mapping",
+        }
+    }
+    let position = mappings[..idx]
+        .iter()
+        .filter(|m| kind(m) == kind(&mappings[idx]))
+        .count();
+    format!("{}[{}]", kind(&mappings[idx]), position)
+}
+
+pub fn format_keys<'a>(keys: impl IntoIterator<Item = &'a KeyCode>) -> String {
+    let mut keys: Vec<String> = keys.into_iter().map(|k| format!("{:?}", k)).collect();
+    keys.sort();
+    keys.join("+")
+}
+
+pub fn format_input(input: &HashSet<KeyCode>, modifiers: &HashSet<Modifier>) -> String {
+    let mut names: Vec<String> = modifiers.iter().map(|m| m.to_string()).collect();
+    names.sort();
+    if !input.is_empty() {
+        names.push(format_keys(input));
+    }
+    names.join("+")
+}
+
+/// Finds the remap rules that can never match because an earlier rule
+/// consumes one of their non-modifier input keys first
+fn check_shadowed(
+    mappings: &[Mapping],
+    modifier_keys: &HashSet<KeyCode>,
+    diags: &mut Vec<Diagnostic>,
+) {
+    for (idx, map) in mappings.iter().enumerate() {
+        let (input, modifiers) = match map {
+            Mapping::Remap {
+                input, modifiers, ..
+            } => (input, modifiers),
+            _ => continue,
+        };
+        for (prior_idx, prior) in mappings[..idx].iter().enumerate() {
+            if let Mapping::Remap {
+                input: prior_input,
+                modifiers: prior_modifiers,
+                ..
+            } = prior
+            {
+                // Each of the prior rule's side agnostic modifiers must be
+                // held whenever this rule matches
+                let modifiers_implied = prior_modifiers
+                    .iter()
+                    .all(|m| modifiers.contains(m) || m.keys().iter().any(|k| input.contains(k)));
+                // A conditional rule only shadows rules that apply
+                // under the same conditions, or narrower ones
+                let conditions_implied = prior
+                    .conditions()
+                    .iter()
+                    .all(|c| map.conditions().contains(c));
+                if modifiers_implied
+                    && conditions_implied
+                    && prior_input.is_subset(input)
+                    && !prior_input.iter().all(|k| modifier_keys.contains(k))
+                {
+                    diags.push(Diagnostic {
+                        severity: Severity::Error,
+                        message: format!(
+                            "{} ({}) is shadowed by {} ({}) and can never match; \
+                             move it before {}",
+                            rule_name(mappings, idx),
+                            format_input(input, modifiers),
+                            rule_name(mappings, prior_idx),
+                            format_input(prior_input, prior_modifiers),
+                            rule_name(mappings, prior_idx),
+                        ),
+                    });
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Dual role mappings are applied before remap rules and consume their
+/// input key, so a remap rule that uses that key can never match
+fn check_dual_role_conflicts(mappings: &[Mapping], diags: &mut Vec<Diagnostic>) {
+    for (dual_idx, dual) in mappings.iter().enumerate() {
+        let dual_input = match dual {
+            Mapping::DualRole { input, .. } => input,
+            _ => continue,
+        };
+        for (idx, map) in mappings.iter().enumerate() {
+            if let Mapping::Remap { input, .. } = map {
+                let conditions_implied = dual
+                    .conditions()
+                    .iter()
+                    .all(|c| map.conditions().contains(c));
+                if input.contains(dual_input) && conditions_implied {
+                    diags.push(Diagnostic {
+                        severity: Severity::Error,
+                        message: format!(
+                            "{} ({}) uses {:?}, which is the input of {} and \
+                             is consumed before remap rules are applied",
+                            rule_name(mappings, idx),
+                            format_keys(input),
+                            dual_input,
+                            rule_name(mappings, dual_idx),
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Disabled keys are swallowed before remap rules are applied, so a
+/// remap rule that needs all of them held can never match
+fn check_disable_conflicts(mappings: &[Mapping], diags: &mut Vec<Diagnostic>) {
+    for (disable_idx, disable) in mappings.iter().enumerate() {
+        let (disable_input, disable_modifiers) = match disable {
+            Mapping::Disable {
+                input, modifiers, ..
+            } => (input, modifiers),
+            _ => continue,
+        };
+        for (idx, map) in mappings.iter().enumerate() {
+            if let Mapping::Remap {
+                input, modifiers, ..
+            } = map
+            {
+                let modifiers_implied = disable_modifiers
+                    .iter()
+                    .all(|m| modifiers.contains(m) || m.keys().iter().any(|k| input.contains(k)));
+                let conditions_implied = disable
+                    .conditions()
+                    .iter()
+                    .all(|c| map.conditions().contains(c));
+                if modifiers_implied && conditions_implied && disable_input.is_subset(input) {
+                    diags.push(Diagnostic {
+                        severity: Severity::Error,
+                        message: format!(
+                            "{} ({}) uses {}, which is disabled by {} and \
+                             is swallowed before remap rules are applied",
+                            rule_name(mappings, idx),
+                            format_input(input, modifiers),
+                            format_input(disable_input, disable_modifiers),
+                            rule_name(mappings, disable_idx),
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn check_empty(mappings: &[Mapping], diags: &mut Vec<Diagnostic>) {
+    for (idx, map) in mappings.iter().enumerate() {
+        let empty = match map {
+            Mapping::DualRole { hold, tap, .. } => {
+                let mut empty = vec![];
+                if hold.is_empty() {
+                    empty.push("hold");
+                }
+                if tap.is_empty() {
+                    empty.push("tap");
+                }
+                empty
+            }
+            Mapping::Remap { output, .. } if output.is_empty() => vec!["output"],
+            _ => vec![],
+        };
+        for field in empty {
+            diags.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("{} has an empty `{}` list", rule_name(mappings, idx), field),
+            });
+        }
+    }
+}
+
+/// Reports keys that are mapped into one another.  Each rule is only
+/// applied once so these don't loop, but they are frequently a mistake
+fn check_cycles(mappings: &[Mapping], diags: &mut Vec<Diagnostic>) {
+    let mut edges: HashMap<KeyCode, HashSet<KeyCode>> = HashMap::new();
+    for map in mappings {
+        if let Mapping::Remap { input, output, .. } = map {
+            for i in input.difference(output) {
+                for o in output.difference(input) {
+                    edges.entry(i.clone()).or_default().insert(o.clone());
+                }
+            }
+        }
+    }
+
+    let mut reported: HashSet<Vec<String>> = HashSet::new();
+    let mut starts: Vec<&KeyCode> = edges.keys().collect();
+    starts.sort_by_key(|k| format!("{:?}", k));
+
+    for start in starts {
+        // Depth first search for a path leading back to `start`
+        let mut stack = vec![vec![start.clone()]];
+        while let Some(path) = stack.pop() {
+            let last = path.last().unwrap();
+            for next in edges.get(last).into_iter().flatten() {
+                if next == start {
+                    let mut key: Vec<String> = path.iter().map(|k| format!("{:?}", k)).collect();
+                    key.sort();
+                    if reported.insert(key) {
+                        let cycle: Vec<String> = path
+                            .iter()
+                            .chain(std::iter::once(start))
+                            .map(|k| format!("{:?}", k))
+                            .collect();
+                        diags.push(Diagnostic {
+                            severity: Severity::Warning,
+                            message: format!(
+                                "keys are remapped in a cycle: {}",
+                                cycle.join(" -> ")
+                            ),
+                        });
+                    }
+                } else if !path.contains(next) {
+                    let mut longer = path.clone();
+                    longer.push(next.clone());
+                    stack.push(longer);
+                }
+            }
+        }
+    }
+}
+
+/// Reports input keys that the devices don't report, and which can
+/// therefore never trigger their rule.  `devices` holds the name of
+/// each matching device along with the keys that it reports.  When
+/// several devices match, a rule that some of them can't trigger is
+/// only a warning, as it can still be used with the others.
+fn check_device_keys(
+    mappings: &[Mapping],
+    devices: &[(String, HashSet<KeyCode>)],
+    diags: &mut Vec<Diagnostic>,
+) {
+    for (idx, map) in mappings.iter().enumerate() {
+        let inputs: Vec<&KeyCode> = match map {
+            Mapping::DualRole { input, .. } => vec![input],
+            Mapping::Remap { input, .. } | Mapping::Disable { input, .. } => input.iter().collect(),
+            _ => vec![],
+        };
+        let lacking: Vec<(&String, Vec<&KeyCode>)> = devices
+            .iter()
+            .filter_map(|(name, keys)| {
+                let missing: Vec<&KeyCode> = inputs
+                    .iter()
+                    .copied()
+                    .filter(|k| !keys.contains(k))
+                    .collect();
+                if missing.is_empty() {
+                    None
+                } else {
+                    Some((name, missing))
+                }
+            })
+            .collect();
+
+        let severity = if lacking.len() == devices.len() {
+            Severity::Error
+        } else {
+            Severity::Warning
+        };
+        for (name, missing) in lacking {
+            let device = if devices.len() == 1 {
+                "the device".to_string()
+            } else {
+                format!("`{}`", name)
+            };
+            diags.push(Diagnostic {
+                severity,
+                message: format!(
+                    "{} uses {}, which {} cannot emit",
+                    rule_name(mappings, idx),
+                    format_keys(missing),
+                    device
+                ),
+            });
+        }
+    }
+}
+
+pub fn check_config(path: &Path) -> Result<()> {
+    let mapping_config = MappingConfig::from_file(path)
+        .context(format!("loading MappingConfig from {}", path.display()))?;
+    let mappings = mapping_config.mappings();
+
+    let mut diags = vec![];
+    check_shadowed(mappings, mapping_config.modifier_keys(), &mut diags);
+    check_dual_role_conflicts(mappings, &mut diags);
+    check_disable_conflicts(mappings, &mut diags);
+    check_empty(mappings, &mut diags);
+    check_cycles(mappings, &mut diags);
+
+    let devices = if mapping_config.device().all_keyboards {
+        DeviceInfo::all_matching(mapping_config.device())
+    } else {
+        DeviceInfo::with_match(mapping_config.device()).map(|info| vec![info])
+    };
+    match devices {
+        Ok(devices) => {
+            let mut device_keys = vec![];
+            for info in devices {
+                match info.key_codes() {
+                    Ok(keys) => device_keys.push((info.name, keys)),
+                    Err(err) => log::warn!(
+                        "Skipping checks against the capabilities of {}: {:#}",
+                        info.path.display(),
+                        err
+                    ),
+                }
+            }
+            if !device_keys.is_empty() {
+                check_device_keys(mappings, &device_keys, &mut diags);
+            }
+        }
+        Err(err) => log::warn!("Skipping checks against the device capabilities: {:#}", err),
+    }
+
+    for diag in &diags {
+        let label = match diag.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("{}: {}", label, diag.message);
+    }
+
+    let errors = diags
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diags.len() - errors;
+    println!(
+        "{}: {} error(s), {} warning(s)",
+        path.display(),
+        errors,
+        warnings
+    );
+
+    if errors > 0 {
+        bail!("{} has {} error(s)", path.display(), errors);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(diags: &[Diagnostic], severity: Severity) -> Vec<&str> {
+        diags
+            .iter()
+            .filter(|d| d.severity == severity)
+            .map(|d| d.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn rule_names_count_each_kind() {
+        let mappings = vec![
+            Mapping::remap([KeyCode::KEY_A], [KeyCode::KEY_B]).build(),
+            Mapping::dual_role(
+                KeyCode::KEY_CAPSLOCK,
+                [KeyCode::KEY_LEFTCTRL],
+                [KeyCode::KEY_ESC],
+            ),
+            Mapping::remap([KeyCode::KEY_C], [KeyCode::KEY_D]).build(),
+            Mapping::disable([KeyCode::KEY_INSERT], false),
+        ];
+        assert_eq!(rule_name(&mappings, 0), "remap[0]");
+        assert_eq!(rule_name(&mappings, 1), "dual_role[0]");
+        assert_eq!(rule_name(&mappings, 2), "remap[1]");
+        assert_eq!(rule_name(&mappings, 3), "disable[0]");
+    }
+
+    #[test]
+    fn shadowed_rule() {
+        let mappings = vec![
+            Mapping::remap(
+                [KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT],
+                [KeyCode::KEY_HOME],
+            )
+            .build(),
+            Mapping::remap(
+                [
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_LEFT,
+                ],
+                [KeyCode::KEY_END],
+            )
+            .build(),
+        ];
+        let mut diags = vec![];
+        check_shadowed(&mappings, &default_modifier_keys(), &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Error),
+            vec![
+                "remap[1] (KEY_LEFT+KEY_LEFTALT+KEY_LEFTSHIFT) is shadowed by \
+                 remap[0] (KEY_LEFT+KEY_LEFTALT) and can never match; move it before remap[0]"
+            ]
+        );
+    }
+
+    #[test]
+    fn more_specific_rule_first_is_not_shadowed() {
+        let mappings = vec![
+            Mapping::remap(
+                [
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_LEFT,
+                ],
+                [KeyCode::KEY_END],
+            )
+            .build(),
+            Mapping::remap(
+                [KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT],
+                [KeyCode::KEY_HOME],
+            )
+            .build(),
+        ];
+        let mut diags = vec![];
+        check_shadowed(&mappings, &default_modifier_keys(), &mut diags);
+        assert!(diags.is_empty());
+    }
+
+    #[test]
+    fn side_agnostic_modifier_shadows() {
+        let mappings = vec![
+            Mapping::remap([KeyCode::KEY_LEFT], [KeyCode::KEY_HOME])
+                .modifiers([Modifier::Alt])
+                .build(),
+            Mapping::remap(
+                [KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFT],
+                [KeyCode::KEY_END],
+            )
+            .build(),
+        ];
+        let mut diags = vec![];
+        check_shadowed(&mappings, &default_modifier_keys(), &mut diags);
+        assert_eq!(messages(&diags, Severity::Error).len(), 1);
+    }
+
+    #[test]
+    fn dual_role_conflict() {
+        let mappings = vec![
+            Mapping::dual_role(
+                KeyCode::KEY_CAPSLOCK,
+                [KeyCode::KEY_LEFTCTRL],
+                [KeyCode::KEY_ESC],
+            ),
+            Mapping::remap([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_A], [KeyCode::KEY_B]).build(),
+        ];
+        let mut diags = vec![];
+        check_dual_role_conflicts(&mappings, &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Error),
+            vec![
+                "remap[0] (KEY_A+KEY_CAPSLOCK) uses KEY_CAPSLOCK, which is the input of \
+                 dual_role[0] and is consumed before remap rules are applied"
+            ]
+        );
+    }
+
+    #[test]
+    fn disable_conflict() {
+        let mappings = vec![
+            Mapping::disable([KeyCode::KEY_INSERT], false),
+            Mapping::remap(
+                [KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_INSERT],
+                [KeyCode::KEY_PASTE],
+            )
+            .build(),
+            Mapping::remap([KeyCode::KEY_DELETE], [KeyCode::KEY_BACKSPACE]).build(),
+        ];
+        let mut diags = vec![];
+        check_disable_conflicts(&mappings, &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Error),
+            vec![
+                "remap[0] (KEY_INSERT+KEY_LEFTSHIFT) uses KEY_INSERT, which is disabled by \
+                 disable[0] and is swallowed before remap rules are applied"
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_lists() {
+        let mappings = vec![
+            Mapping::dual_role(KeyCode::KEY_CAPSLOCK, [], [KeyCode::KEY_ESC]),
+            Mapping::remap([KeyCode::KEY_A], []).build(),
+        ];
+        let mut diags = vec![];
+        check_empty(&mappings, &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Warning),
+            vec![
+                "dual_role[0] has an empty `hold` list",
+                "remap[0] has an empty `output` list"
+            ]
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported_once() {
+        let mappings = vec![
+            Mapping::remap([KeyCode::KEY_A], [KeyCode::KEY_B]).build(),
+            Mapping::remap([KeyCode::KEY_B], [KeyCode::KEY_A]).build(),
+            Mapping::remap([KeyCode::KEY_C], [KeyCode::KEY_D]).build(),
+        ];
+        let mut diags = vec![];
+        check_cycles(&mappings, &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Warning),
+            vec!["keys are remapped in a cycle: KEY_A -> KEY_B -> KEY_A"]
+        );
+    }
+
+    #[test]
+    fn device_keys_compare_codes() {
+        // BTN_A is an alias of BTN_SOUTH, which is what the device reports
+        let btn_a = parse_key("BTN_A").unwrap();
+        let mappings = vec![Mapping::remap([btn_a], [KeyCode::KEY_ENTER]).build()];
+        let keys: HashSet<KeyCode> = [KeyCode::BTN_SOUTH].iter().cloned().collect();
+        let mut diags = vec![];
+        check_device_keys(&mappings, &[("Gamepad".to_string(), keys)], &mut diags);
+        assert!(diags.is_empty());
+    }
+
+    #[test]
+    fn device_keys_missing() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_F13], [KeyCode::KEY_A]).build()];
+        let keys: HashSet<KeyCode> = [KeyCode::KEY_A].iter().cloned().collect();
+        let mut diags = vec![];
+        check_device_keys(&mappings, &[("Keyboard".to_string(), keys)], &mut diags);
+        assert_eq!(
+            messages(&diags, Severity::Error),
+            vec!["remap[0] uses KEY_F13, which the device cannot emit"]
+        );
+    }
+
+    #[test]
+    fn device_keys_missing_from_some_devices() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_F13], [KeyCode::KEY_A]).build()];
+        let full: HashSet<KeyCode> = [KeyCode::KEY_A, KeyCode::KEY_F13].iter().cloned().collect();
+        let small: HashSet<KeyCode> = [KeyCode::KEY_A].iter().cloned().collect();
+        let devices = vec![
+            ("Full".to_string(), full),
+            ("Small".to_string(), small.clone()),
+        ];
+        let mut diags = vec![];
+        check_device_keys(&mappings, &devices, &mut diags);
+        assert!(messages(&diags, Severity::Error).is_empty());
+        assert_eq!(
+            messages(&diags, Severity::Warning),
+            vec!["remap[0] uses KEY_F13, which `Small` cannot emit"]
+        );
+
+        let devices = vec![
+            ("Small".to_string(), small.clone()),
+            ("Other".to_string(), small),
+        ];
+        let mut diags = vec![];
+        check_device_keys(&mappings, &devices, &mut diags);
+        assert_eq!(messages(&diags, Severity::Error).len(), 2);
+    }
+}
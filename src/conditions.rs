@@ -0,0 +1,208 @@
+use crate::deviceinfo::{is_virtual_device, NameMatch};
+use crate::mapping::*;
+use evdev_rs::Device;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// How often the switches and connected devices are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default, PartialEq)]
+struct Snapshot {
+    /// A switch is on if it is on for any device that has it
+    switches: HashMap<SwitchCode, bool>,
+    device_names: Vec<String>,
+}
+
+/// The devices that the switches are read from.  Each device is opened
+/// once and kept open, so that its switches can still be read after
+/// privileges have been dropped.
+#[derive(Debug, Default)]
+struct Sources {
+    /// The devices in /dev/input that we have looked at, by path.  This
+    /// is None for those that don't have any of the switches, or that
+    /// couldn't be opened.
+    devices: HashMap<PathBuf, Option<File>>,
+}
+
+/// What the background thread needs, until it is started
+#[derive(Debug)]
+struct Pending {
+    sources: Sources,
+    switches: HashSet<SwitchCode>,
+}
+
+/// Tracks the parts of the system that mapping conditions depend on.
+/// Once `refresh_in_background` is called, the state is refreshed by
+/// a background thread for as long as any clone of it is alive.
+#[derive(Debug, Clone, Default)]
+pub struct ConditionState {
+    snapshot: Arc<Mutex<Snapshot>>,
+    pending: Arc<Mutex<Option<Pending>>>,
+}
+
+impl ConditionState {
+    /// Starts tracking the switches and devices used by the conditions
+    /// of `mappings`.  If none of them have conditions then nothing
+    /// is tracked.
+    pub fn start(mappings: &[Mapping]) -> Self {
+        let state = Self::default();
+        let conditions: Vec<&Condition> = mappings.iter().flat_map(|m| m.conditions()).collect();
+        if conditions.is_empty() {
+            return state;
+        }
+
+        let switches: HashSet<SwitchCode> = conditions
+            .iter()
+            .filter_map(|c| match c {
+                Condition::Switch { switch, .. } => Some(switch.clone()),
+                Condition::Device { .. } => None,
+            })
+            .collect();
+
+        // This happens before privileges are dropped, so the switch
+        // devices that are connected now can be opened
+        let mut sources = Sources::default();
+        *state.snapshot.lock().unwrap() = scan(&mut sources, &switches);
+        *state.pending.lock().unwrap() = Some(Pending { sources, switches });
+        state
+    }
+
+    /// Starts the thread that refreshes the state.  This is separate
+    /// from `start` so that the thread can be started once privileges
+    /// have been dropped, as a thread keeps the capabilities that it
+    /// was started with.
+    pub fn refresh_in_background(&self) {
+        if let Some(Pending { sources, switches }) = self.pending.lock().unwrap().take() {
+            let snapshot = Arc::downgrade(&self.snapshot);
+            std::thread::spawn(move || refresh(snapshot, sources, switches));
+        }
+    }
+
+    /// Returns true if all of `conditions` are currently met
+    pub fn is_met(&self, conditions: &[Condition]) -> bool {
+        if conditions.is_empty() {
+            return true;
+        }
+        let snapshot = self.snapshot.lock().unwrap();
+        conditions.iter().all(|condition| match condition {
+            Condition::Switch { switch, on } => {
+                snapshot.switches.get(switch).copied().unwrap_or(false) == *on
+            }
+            Condition::Device { name, present } => {
+                let name = NameMatch::from_name(name.clone());
+                snapshot.device_names.iter().any(|n| name.matches(n)) == *present
+            }
+        })
+    }
+}
+
+fn refresh(snapshot: Weak<Mutex<Snapshot>>, mut sources: Sources, switches: HashSet<SwitchCode>) {
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let latest = scan(&mut sources, &switches);
+        let snapshot = match snapshot.upgrade() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let mut snapshot = snapshot.lock().unwrap();
+        if *snapshot != latest {
+            log::debug!("Conditions changed: {:?}", latest);
+            *snapshot = latest;
+        }
+    }
+}
+
+/// Reads a property of the input device `event`, such as `name`, from
+/// sysfs, which unlike the device node is readable by anyone
+fn read_sysfs(event: &str, property: &str) -> Option<String> {
+    let path = Path::new("/sys/class/input")
+        .join(event)
+        .join("device")
+        .join(property);
+    let value = std::fs::read_to_string(path).ok()?;
+    Some(value.trim_end_matches('\n').to_string())
+}
+
+/// Opens the device at `path` if it has any of `switches`
+fn open_switch_device(path: &Path, switches: &HashSet<SwitchCode>) -> Option<File> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            log::debug!("Unable to read switches from {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    let device = Device::new_from_fd(file.try_clone().ok()?).ok()?;
+    switches
+        .iter()
+        .any(|switch| device.has_event_code(&EventCode::EV_SW(switch.clone())))
+        .then_some(file)
+}
+
+/// Reads the names of the connected devices and the state of `switches`.
+/// Devices that appear in /dev/input are opened, if there are switches
+/// to read, and those that have gone are forgotten.
+fn scan(sources: &mut Sources, switches: &HashSet<SwitchCode>) -> Snapshot {
+    let mut snapshot = Snapshot::default();
+    let entries = match std::fs::read_dir("/dev/input") {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("Unable to list /dev/input: {}", err);
+            return snapshot;
+        }
+    };
+
+    let mut present = HashSet::new();
+    for entry in entries.flatten() {
+        let event = entry.file_name().to_string_lossy().to_string();
+        if !event.starts_with("event") {
+            continue;
+        }
+        // Devices come and go, so failing to read one isn't an error
+        let name = match read_sysfs(&event, "name") {
+            Some(name) => name,
+            None => continue,
+        };
+        let phys = read_sysfs(&event, "phys").unwrap_or_default();
+        if is_virtual_device(&name, &phys) {
+            continue;
+        }
+
+        let path = entry.path();
+        present.insert(path.clone());
+        if !switches.is_empty() {
+            let file = sources
+                .devices
+                .entry(path.clone())
+                .or_insert_with(|| open_switch_device(&path, switches));
+            // A new Device reads the current state of the switches
+            let device = file
+                .as_ref()
+                .and_then(|file| file.try_clone().ok())
+                .and_then(|file| Device::new_from_fd(file).ok());
+            // The device went away, and perhaps another took its place,
+            // so it will be opened again on the next scan
+            if file.is_some() && device.is_none() {
+                sources.devices.remove(&path);
+            }
+            if let Some(device) = device {
+                for switch in switches {
+                    let code = EventCode::EV_SW(switch.clone());
+                    if device.has_event_code(&code) {
+                        let on = device.event_value(&code).unwrap_or(0) != 0;
+                        *snapshot.switches.entry(switch.clone()).or_insert(false) |= on;
+                    }
+                }
+            }
+        }
+        snapshot.device_names.push(name);
+    }
+    sources.devices.retain(|path, _| present.contains(path));
+
+    snapshot.device_names.sort();
+    snapshot
+}
@@ -1,58 +1,344 @@
 use anyhow::{anyhow, bail, Context, Result};
-use evdev_rs::Device;
+use evdev_rs::enums::{EventCode, EventType, EV_KEY as KeyCode};
+use evdev_rs::{Device, GrabMode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
     pub name: String,
     pub path: PathBuf,
     pub phys: String,
+    pub uniq: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    /// true if the device reports the alphabetic keys
+    /// that we'd expect to find on a keyboard
+    pub is_keyboard: bool,
+}
+
+/// Describes what a device is capable of reporting
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCapabilities {
+    pub event_types: Vec<String>,
+    pub keys: Vec<String>,
+    pub leds: Vec<String>,
+    pub switches: Vec<String>,
+    /// true if another process holds an exclusive grab on the device.
+    /// This is only checked on request, as checking has side effects.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grabbed: Option<bool>,
+}
+
+/// The prefix of the default name of the virtual output devices that
+/// we create.
+pub const VIRTUAL_DEVICE_NAME_PREFIX: &str = "evremap Virtual input";
+
+/// The prefix of the phys of every virtual output device that we create.
+/// Unlike the name, it can't be configured away, so it is what we use to
+/// avoid selecting our own output devices as input devices.
+pub const VIRTUAL_DEVICE_PHYS_PREFIX: &str = "evremap/";
+
+/// Returns the phys to give to a virtual output device, which is `phys`
+/// tagged with `VIRTUAL_DEVICE_PHYS_PREFIX`
+pub(crate) fn virtual_device_phys(phys: &str) -> String {
+    if phys.starts_with(VIRTUAL_DEVICE_PHYS_PREFIX) {
+        phys.to_string()
+    } else {
+        format!("{}{}", VIRTUAL_DEVICE_PHYS_PREFIX, phys)
+    }
+}
+
+/// Returns true if the device is one of our virtual output devices.
+/// The name is checked too for the devices of older versions, which
+/// didn't tag the phys.
+pub(crate) fn is_virtual_device(name: &str, phys: &str) -> bool {
+    phys.starts_with(VIRTUAL_DEVICE_PHYS_PREFIX) || name.starts_with(VIRTUAL_DEVICE_NAME_PREFIX)
+}
+
+/// Keys that a device must report to be considered a keyboard
+const TYPICAL_KEYBOARD_KEYS: &[KeyCode] = &[
+    KeyCode::KEY_Q,
+    KeyCode::KEY_A,
+    KeyCode::KEY_Z,
+    KeyCode::KEY_SPACE,
+];
+
+/// Describes how to pick the input device from the set of
+/// available devices.  Each field that is set must match;
+/// fields that are not set are not considered.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMatch {
+    pub name: Option<NameMatch>,
+    pub phys: Option<String>,
+    pub uniq: Option<String>,
+    pub bustype: Option<u16>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub version: Option<u16>,
+    /// A path to the device node, or a symlink to it, such as
+    /// the entries in `/dev/input/by-id`
+    pub path: Option<PathBuf>,
+    /// Select every device that looks like a keyboard
+    pub all_keyboards: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum NameMatch {
+    Exact(String),
+    /// A shell style pattern where `*` matches any sequence
+    /// of characters and `?` matches any single character
+    Glob(String),
+    Regex(Regex),
+}
+
+impl NameMatch {
+    /// Interprets `name` as a glob if it contains glob
+    /// metacharacters, otherwise as an exact name
+    pub fn from_name(name: String) -> Self {
+        if name.contains(['*', '?']) {
+            Self::Glob(name)
+        } else {
+            Self::Exact(name)
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(n) => n == name,
+            Self::Glob(pattern) => glob_match(pattern, name),
+            Self::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+impl std::fmt::Display for NameMatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Exact(n) | Self::Glob(n) => write!(fmt, "name=`{}`", n),
+            Self::Regex(re) => write!(fmt, "name=~`{}`", re),
+        }
+    }
+}
+
+/// Matches `text` against a shell style `pattern` supporting
+/// the `*` and `?` wildcards
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the most recent `*` in the pattern, and the
+    // position in the text that it is currently matched up to
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the star consume one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl DeviceMatch {
+    /// Matches devices by name, which may contain `*` and `?` wildcards
+    pub fn by_name(name: impl Into<String>) -> Self {
+        Self {
+            name: Some(NameMatch::from_name(name.into())),
+            ..Default::default()
+        }
+    }
+
+    /// Matches the device at `path`, or the device that it links to
+    pub fn by_path(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Matches every device that looks like a keyboard
+    pub fn all_keyboards() -> Self {
+        Self {
+            all_keyboards: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.phys.is_none()
+            && self.uniq.is_none()
+            && self.bustype.is_none()
+            && self.vendor.is_none()
+            && self.product.is_none()
+            && self.version.is_none()
+            && self.path.is_none()
+            && !self.all_keyboards
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        if is_virtual_device(&info.name, &info.phys) {
+            return false;
+        }
+        if self.all_keyboards && !info.is_keyboard {
+            return false;
+        }
+        if let Some(name) = &self.name {
+            if !name.matches(&info.name) {
+                return false;
+            }
+        }
+        if let Some(phys) = &self.phys {
+            if *phys != info.phys {
+                return false;
+            }
+        }
+        if let Some(uniq) = &self.uniq {
+            if *uniq != info.uniq {
+                return false;
+            }
+        }
+        let ids = [
+            (self.bustype, info.bustype),
+            (self.vendor, info.vendor),
+            (self.product, info.product),
+            (self.version, info.version),
+        ];
+        for (wanted, actual) in &ids {
+            if let Some(wanted) = wanted {
+                if wanted != actual {
+                    return false;
+                }
+            }
+        }
+        if let Some(path) = &self.path {
+            // Resolve symlinks such as those in /dev/input/by-id
+            match std::fs::canonicalize(path) {
+                Ok(path) if path == info.path => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl std::fmt::Display for DeviceMatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut terms = vec![];
+        if self.all_keyboards {
+            terms.push("all-keyboards".to_string());
+        }
+        if let Some(name) = &self.name {
+            terms.push(name.to_string());
+        }
+        if let Some(phys) = &self.phys {
+            terms.push(format!("phys=`{}`", phys));
+        }
+        if let Some(uniq) = &self.uniq {
+            terms.push(format!("uniq=`{}`", uniq));
+        }
+        if let Some(bustype) = self.bustype {
+            terms.push(format!("bustype=0x{:04x}", bustype));
+        }
+        if let Some(vendor) = self.vendor {
+            terms.push(format!("vendor=0x{:04x}", vendor));
+        }
+        if let Some(product) = self.product {
+            terms.push(format!("product=0x{:04x}", product));
+        }
+        if let Some(version) = self.version {
+            terms.push(format!("version=0x{:04x}", version));
+        }
+        if let Some(path) = &self.path {
+            terms.push(format!("path=`{}`", path.display()));
+        }
+        write!(fmt, "{}", terms.join(", "))
+    }
+}
+
+/// Opens the input device at `path`.  When `writable` is set we try
+/// to open it for writing, which is required to control its LEDs, but
+/// fall back to reading only.
+pub(crate) fn open_device(path: &Path, writable: bool) -> Result<Device> {
+    let f = if writable {
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+        {
+            Ok(f) => Some(f),
+            Err(err) => {
+                log::warn!(
+                    "Unable to open {} for writing, so its LEDs cannot be controlled: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let f = match f {
+        Some(f) => f,
+        None => std::fs::File::open(path).context(format!("opening {}", path.display()))?,
+    };
+    let mut input = Device::new().ok_or_else(|| anyhow!("failed to make new Device"))?;
+    input
+        .set_fd(f)
+        .context(format!("assigning fd for {} to Device", path.display()))?;
+    Ok(input)
 }
 
 impl DeviceInfo {
     pub fn with_path(path: PathBuf) -> Result<Self> {
-        let f = std::fs::File::open(&path).context(format!("opening {}", path.display()))?;
-        let mut input = Device::new().ok_or_else(|| anyhow!("failed to make new Device"))?;
-        input
-            .set_fd(f)
-            .context(format!("assigning fd for {} to Device", path.display()))?;
+        let input = open_device(&path, false)?;
 
         Ok(Self {
             name: input.name().unwrap_or("").to_string(),
             phys: input.phys().unwrap_or("").to_string(),
+            uniq: input.uniq().unwrap_or("").to_string(),
+            bustype: input.bustype(),
+            vendor: input.vendor_id(),
+            product: input.product_id(),
+            version: input.version(),
+            is_keyboard: input.has_event_type(&EventType::EV_KEY)
+                && TYPICAL_KEYBOARD_KEYS
+                    .iter()
+                    .all(|key| input.has_event_code(&EventCode::EV_KEY(key.clone()))),
             path,
         })
     }
 
-    pub fn with_name(name: &str, phys: Option<&str>) -> Result<Self> {
-        let mut devices = Self::obtain_device_list()?;
-
-        if let Some(phys) = phys {
-            match devices.iter().position(|item| item.phys == phys) {
-                Some(idx) => return Ok(devices.remove(idx)),
-                None => {
-                    bail!(
-                        "Requested device `{}` with phys=`{}` was not found",
-                        name,
-                        phys
-                    );
-                }
-            }
-        }
-
-        let mut devices_with_name: Vec<_> = devices
-            .into_iter()
-            .filter(|item| item.name == name)
-            .collect();
+    pub fn with_match(criteria: &DeviceMatch) -> Result<Self> {
+        Self::select_first(Self::obtain_device_list()?, criteria)
+    }
 
-        if devices_with_name.is_empty() {
-            bail!("No device found with name `{}`", name);
-        }
+    /// Picks the first of `devices` that matches `criteria`, warning
+    /// if there are others
+    pub fn select_first(devices: Vec<Self>, criteria: &DeviceMatch) -> Result<Self> {
+        let mut matching = Self::select_all(devices, criteria)?;
 
-        if devices_with_name.len() > 1 {
-            log::warn!("The following devices match name `{}`:", name);
-            for dev in &devices_with_name {
+        if matching.len() > 1 {
+            log::warn!("The following devices match {}:", criteria);
+            for dev in &matching {
                 log::warn!("{:?}", dev);
             }
             log::warn!(
@@ -60,14 +346,96 @@ impl DeviceInfo {
                        use one of the others, add the corresponding phys \
                        value to your configuration, for example, \
                        `phys = \"{}\"` for the second entry in the list.",
-                devices_with_name[1].phys
+                matching[1].phys
             );
         }
 
-        Ok(devices_with_name.remove(0))
+        Ok(matching.remove(0))
+    }
+
+    /// Re-opens the device to query its capabilities.
+    /// If `check_grab` is true, the device is briefly grabbed in order
+    /// to determine whether some other process already has it grabbed.
+    /// While it is grabbed, its events are lost to everything else.
+    pub fn capabilities(&self, check_grab: bool) -> Result<DeviceCapabilities> {
+        let mut input = open_device(&self.path, false)?;
+
+        let mut event_types = vec![];
+        let mut keys = vec![];
+        let mut leds = vec![];
+        let mut switches = vec![];
+
+        for event_type in EventType::EV_SYN.iter() {
+            if input.has_event_type(&event_type) {
+                event_types.push(event_type.to_string());
+            }
+        }
+        for code in EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT).iter() {
+            if !input.has_event_code(&code) {
+                continue;
+            }
+            match code {
+                EventCode::EV_KEY(_) => keys.push(code.to_string()),
+                EventCode::EV_LED(_) => leds.push(code.to_string()),
+                EventCode::EV_SW(_) => switches.push(code.to_string()),
+                _ => {}
+            }
+        }
+
+        let grabbed = if check_grab {
+            Some(match input.grab(GrabMode::Grab) {
+                Ok(()) => {
+                    input.grab(GrabMode::Ungrab).ok();
+                    false
+                }
+                Err(err) => err as i32 == libc::EBUSY,
+            })
+        } else {
+            None
+        };
+
+        Ok(DeviceCapabilities {
+            event_types,
+            keys,
+            leds,
+            switches,
+            grabbed,
+        })
+    }
+
+    /// Re-opens the device to find the keys and buttons that it reports
+    pub fn key_codes(&self) -> Result<HashSet<KeyCode>> {
+        let input = open_device(&self.path, false)?;
+        Ok(EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT)
+            .iter()
+            .filter(|code| input.has_event_code(code))
+            .filter_map(|code| match code {
+                EventCode::EV_KEY(key) => Some(key),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Returns every device that matches `criteria`
+    pub fn all_matching(criteria: &DeviceMatch) -> Result<Vec<Self>> {
+        Self::select_all(Self::obtain_device_list()?, criteria)
     }
 
-    fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
+    /// Returns those of `devices` that match `criteria`
+    pub fn select_all(devices: Vec<Self>, criteria: &DeviceMatch) -> Result<Vec<Self>> {
+        let matching: Vec<_> = devices
+            .into_iter()
+            .filter(|item| criteria.matches(item))
+            .collect();
+
+        if matching.is_empty() {
+            bail!("No device found matching {}", criteria);
+        }
+
+        Ok(matching)
+    }
+
+    pub(crate) fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
         let mut devices = vec![];
         for entry in std::fs::read_dir("/dev/input")? {
             let entry = entry?;
@@ -103,7 +471,7 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -113,13 +481,233 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
     }
 }
 
-pub fn list_devices() -> Result<()> {
-    let devices = DeviceInfo::obtain_device_list()?;
-    for item in &devices {
+/// Returns the `/dev/input/by-id` symlinks that resolve to `path`
+fn by_id_paths(path: &Path) -> Vec<PathBuf> {
+    let mut links = vec![];
+    if let Ok(dir) = std::fs::read_dir("/dev/input/by-id") {
+        for entry in dir.flatten() {
+            let link = entry.path();
+            if let Ok(target) = std::fs::canonicalize(&link) {
+                if target == path {
+                    links.push(link);
+                }
+            }
+        }
+    }
+    links.sort();
+    links
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("invalid format `{}`; expected `text` or `json`", s),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceReport {
+    #[serde(flatten)]
+    info: DeviceInfo,
+    by_id: Vec<PathBuf>,
+    #[serde(flatten)]
+    capabilities: Option<DeviceCapabilities>,
+}
+
+/// Lists the devices.  `verbose` includes their capabilities, and
+/// `check_grab` whether they are grabbed, which briefly grabs them.
+pub fn list_devices(
+    format: OutputFormat,
+    verbose: bool,
+    check_grab: bool,
+    keyboards_only: bool,
+) -> Result<()> {
+    let mut reports = vec![];
+    for info in DeviceInfo::obtain_device_list()? {
+        if keyboards_only && !info.is_keyboard {
+            continue;
+        }
+        let capabilities = if verbose || check_grab {
+            match info.capabilities(check_grab) {
+                Ok(capabilities) => Some(capabilities),
+                Err(err) => {
+                    // The device may have gone away since it was listed
+                    log::error!("Skipping {}: {:#}", info.path.display(), err);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        reports.push(DeviceReport {
+            by_id: by_id_paths(&info.path),
+            info,
+            capabilities,
+        });
+    }
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+
+    for report in &reports {
+        let item = &report.info;
         println!("Name: {}", item.name);
         println!("Path: {}", item.path.display());
+        for link in &report.by_id {
+            println!("By-id: {}", link.display());
+        }
         println!("Phys: {}", item.phys);
+        if !item.uniq.is_empty() {
+            println!("Uniq: {}", item.uniq);
+        }
+        println!(
+            "Bustype: 0x{:04x} Vendor: 0x{:04x} Product: 0x{:04x} Version: 0x{:04x}",
+            item.bustype, item.vendor, item.product, item.version
+        );
+        if let Some(caps) = &report.capabilities {
+            println!("Keyboard: {}", if item.is_keyboard { "yes" } else { "no" });
+            if let Some(grabbed) = caps.grabbed {
+                println!("Grabbed: {}", if grabbed { "yes" } else { "no" });
+            }
+            println!("Events: {}", caps.event_types.join(" "));
+            if !caps.keys.is_empty() {
+                println!("Keys: {}", caps.keys.join(" "));
+            }
+            if !caps.leds.is_empty() {
+                println!("LEDs: {}", caps.leds.join(" "));
+            }
+            if !caps.switches.is_empty() {
+                println!("Switches: {}", caps.switches.join(" "));
+            }
+        }
         println!();
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard(name: &str) -> DeviceInfo {
+        DeviceInfo {
+            name: name.to_string(),
+            path: PathBuf::from("/dev/input/event3"),
+            phys: "usb-0000:00:14.0-1/input0".to_string(),
+            uniq: String::new(),
+            bustype: 0x3,
+            vendor: 0x46d,
+            product: 0xc52b,
+            version: 0x111,
+            is_keyboard: true,
+        }
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_match("Logitech K120", "Logitech K120"));
+        assert!(!glob_match("Logitech K120", "Logitech K12"));
+        assert!(!glob_match("Logitech K12", "Logitech K120"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("Logitech *", "Logitech USB Receiver"));
+        assert!(glob_match("*Keyboard*", "AT Translated Set 2 Keyboard"));
+        assert!(glob_match("*Keyboard*", "Keyboard"));
+        assert!(!glob_match("*Keyboard*", "Mouse"));
+        assert!(glob_match("event?", "event3"));
+        assert!(!glob_match("event?", "event12"));
+        assert!(glob_match("event??", "event12"));
+        assert!(glob_match("a**b", "ab"));
+    }
+
+    #[test]
+    fn glob_backtracks() {
+        // The first `b` isn't the one that the star should stop at
+        assert!(glob_match("a*bc", "abxbc"));
+        assert!(glob_match("*a*b", "xaxaxb"));
+        assert!(!glob_match("*a*b", "xaxaxbx"));
+        assert!(glob_match("*?", "x"));
+        assert!(!glob_match("*??", "x"));
+    }
+
+    #[test]
+    fn name_match_from_name() {
+        assert!(matches!(
+            NameMatch::from_name("AT Translated".to_string()),
+            NameMatch::Exact(_)
+        ));
+        assert!(matches!(
+            NameMatch::from_name("AT *".to_string()),
+            NameMatch::Glob(_)
+        ));
+        assert!(matches!(
+            NameMatch::from_name("event?".to_string()),
+            NameMatch::Glob(_)
+        ));
+    }
+
+    #[test]
+    fn name_match_matches() {
+        let exact = NameMatch::from_name("Keyboard".to_string());
+        assert!(exact.matches("Keyboard"));
+        assert!(!exact.matches("USB Keyboard"));
+
+        let glob = NameMatch::from_name("*Keyboard".to_string());
+        assert!(glob.matches("USB Keyboard"));
+        assert!(!glob.matches("USB Keyboard Consumer Control"));
+
+        let regex = NameMatch::Regex(Regex::new("^Logitech .* Keyboard$").unwrap());
+        assert!(regex.matches("Logitech USB Keyboard"));
+        assert!(!regex.matches("Logitech USB Keyboard Consumer Control"));
+    }
+
+    #[test]
+    fn device_match_ids() {
+        let device = keyboard("Logitech USB Keyboard");
+        let mut criteria = DeviceMatch::by_name("Logitech*");
+        criteria.vendor = Some(0x46d);
+        assert!(criteria.matches(&device));
+        criteria.product = Some(0x1234);
+        assert!(!criteria.matches(&device));
+    }
+
+    #[test]
+    fn device_match_skips_virtual_devices() {
+        let device = keyboard(&format!(
+            "{} for /dev/input/event3",
+            VIRTUAL_DEVICE_NAME_PREFIX
+        ));
+        assert!(!DeviceMatch::by_name("*").matches(&device));
+        assert!(!DeviceMatch::all_keyboards().matches(&device));
+    }
+
+    #[test]
+    fn device_match_skips_renamed_virtual_devices() {
+        let mut device = keyboard("Remapped Keyboard");
+        device.phys = virtual_device_phys("kbd0");
+        assert_eq!(device.phys, "evremap/kbd0");
+        assert!(!DeviceMatch::by_name("Remapped Keyboard").matches(&device));
+        assert!(!DeviceMatch::all_keyboards().matches(&device));
+
+        // The phys is only tagged once
+        assert_eq!(virtual_device_phys("evremap/kbd0"), "evremap/kbd0");
+    }
+}
@@ -0,0 +1,561 @@
+use crate::check::{format_input, format_keys, rule_name};
+use anyhow::{Context, Result};
+use evremap::mapping::*;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// A row of the keyboard diagram; each key has its name, without the
+/// `KEY_` prefix, and its width in key units.  An empty name is a gap.
+type Row = &'static [(&'static str, f32)];
+
+const MAIN_ROWS: &[Row] = &[
+    &[
+        ("ESC", 1.),
+        ("", 1.),
+        ("F1", 1.),
+        ("F2", 1.),
+        ("F3", 1.),
+        ("F4", 1.),
+        ("", 0.5),
+        ("F5", 1.),
+        ("F6", 1.),
+        ("F7", 1.),
+        ("F8", 1.),
+        ("", 0.5),
+        ("F9", 1.),
+        ("F10", 1.),
+        ("F11", 1.),
+        ("F12", 1.),
+    ],
+    &[
+        ("GRAVE", 1.),
+        ("1", 1.),
+        ("2", 1.),
+        ("3", 1.),
+        ("4", 1.),
+        ("5", 1.),
+        ("6", 1.),
+        ("7", 1.),
+        ("8", 1.),
+        ("9", 1.),
+        ("0", 1.),
+        ("MINUS", 1.),
+        ("EQUAL", 1.),
+        ("BACKSPACE", 2.),
+    ],
+    &[
+        ("TAB", 1.5),
+        ("Q", 1.),
+        ("W", 1.),
+        ("E", 1.),
+        ("R", 1.),
+        ("T", 1.),
+        ("Y", 1.),
+        ("U", 1.),
+        ("I", 1.),
+        ("O", 1.),
+        ("P", 1.),
+        ("LEFTBRACE", 1.),
+        ("RIGHTBRACE", 1.),
+        ("BACKSLASH", 1.5),
+    ],
+    &[
+        ("CAPSLOCK", 1.75),
+        ("A", 1.),
+        ("S", 1.),
+        ("D", 1.),
+        ("F", 1.),
+        ("G", 1.),
+        ("H", 1.),
+        ("J", 1.),
+        ("K", 1.),
+        ("L", 1.),
+        ("SEMICOLON", 1.),
+        ("APOSTROPHE", 1.),
+        ("ENTER", 2.25),
+    ],
+    &[
+        ("LEFTSHIFT", 2.25),
+        ("Z", 1.),
+        ("X", 1.),
+        ("C", 1.),
+        ("V", 1.),
+        ("B", 1.),
+        ("N", 1.),
+        ("M", 1.),
+        ("COMMA", 1.),
+        ("DOT", 1.),
+        ("SLASH", 1.),
+        ("RIGHTSHIFT", 2.75),
+    ],
+    &[
+        ("LEFTCTRL", 1.25),
+        ("LEFTMETA", 1.25),
+        ("LEFTALT", 1.25),
+        ("SPACE", 6.25),
+        ("RIGHTALT", 1.25),
+        ("RIGHTMETA", 1.25),
+        ("COMPOSE", 1.25),
+        ("RIGHTCTRL", 1.25),
+    ],
+];
+
+const NAV_ROWS: &[Row] = &[
+    &[("SYSRQ", 1.), ("SCROLLLOCK", 1.), ("PAUSE", 1.)],
+    &[("INSERT", 1.), ("HOME", 1.), ("PAGEUP", 1.)],
+    &[("DELETE", 1.), ("END", 1.), ("PAGEDOWN", 1.)],
+    &[],
+    &[("", 1.), ("UP", 1.)],
+    &[("LEFT", 1.), ("DOWN", 1.), ("RIGHT", 1.)],
+];
+
+/// The horizontal position of the navigation cluster, in key units
+const NAV_OFFSET: f32 = 15.25;
+/// The size of a key unit in the diagram, in pixels
+const KEY_UNIT: f32 = 48.;
+
+/// Formats keys with the modifiers first and without the `KEY_`
+/// prefix, for example `LEFTCTRL+C`
+fn short_keys<'a>(
+    keys: impl IntoIterator<Item = &'a KeyCode>,
+    modifier_keys: &HashSet<KeyCode>,
+) -> String {
+    let mut keys: Vec<(bool, String)> = keys
+        .into_iter()
+        .map(|k| {
+            let name = format!("{:?}", k);
+            let name = name.strip_prefix("KEY_").unwrap_or(&name).to_string();
+            (!modifier_keys.contains(k), name)
+        })
+        .collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|(_, name)| name)
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+fn describe_options(options: &ModifierOptions) -> String {
+    let mut parts = vec![];
+    for (label, keys) in [
+        ("preserve", &options.preserve),
+        ("suppress", &options.suppress),
+        ("add", &options.add),
+    ] {
+        if !keys.is_empty() {
+            parts.push(format!("{} {}", label, format_keys(keys)));
+        }
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", parts.join("; "))
+    }
+}
+
+fn describe_conditions(conditions: &[Condition]) -> String {
+    if conditions.is_empty() {
+        return String::new();
+    }
+    let conditions: Vec<String> = conditions.iter().map(|c| c.to_string()).collect();
+    format!(" when {}", conditions.join(" and "))
+}
+
+/// The remap rules that apply while a particular set of
+/// modifiers is held
+#[derive(Default)]
+struct Layer {
+    /// The label to show on a key, keyed by the key name
+    /// without its `KEY_` prefix
+    keys: BTreeMap<String, String>,
+    /// The names of the modifier keys that select this layer
+    held: HashSet<String>,
+    /// Rules that don't fit on a single key of the diagram, or
+    /// that only apply under some conditions
+    other: Vec<String>,
+}
+
+/// Assigns the mappings to layers according to the modifiers that
+/// they require.  The base layer, which requires no modifiers, sorts first.
+fn build_layers(config: &MappingConfig) -> BTreeMap<(usize, String), Layer> {
+    let mut layers: BTreeMap<(usize, String), Layer> = BTreeMap::new();
+    let on_diagram: HashSet<&str> = MAIN_ROWS
+        .iter()
+        .chain(NAV_ROWS.iter())
+        .flat_map(|row| row.iter().map(|(name, _)| *name))
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    for map in config.mappings() {
+        match map {
+            Mapping::DualRole {
+                input, hold, tap, ..
+            } => {
+                let name = short_keys([input], config.modifier_keys());
+                let label = format!(
+                    "tap {}\nhold {}",
+                    short_keys(tap, config.modifier_keys()),
+                    short_keys(hold, config.modifier_keys())
+                );
+                let layer = layers.entry((0, String::new())).or_default();
+                if on_diagram.contains(name.as_str()) && map.conditions().is_empty() {
+                    layer.keys.entry(name).or_insert(label);
+                } else {
+                    layer.other.push(format!(
+                        "{}: {}{}",
+                        name,
+                        label.replace('\n', ", "),
+                        describe_conditions(map.conditions())
+                    ));
+                }
+            }
+            Mapping::Remap {
+                input, modifiers, ..
+            }
+            | Mapping::Disable {
+                input, modifiers, ..
+            } => {
+                let (held, keys): (Vec<&KeyCode>, Vec<&KeyCode>) = input
+                    .iter()
+                    .partition(|k| config.modifier_keys().contains(k));
+                let (held, keys) = if keys.is_empty() {
+                    (vec![], held)
+                } else {
+                    (held, keys)
+                };
+
+                let mut held: Vec<String> = held
+                    .iter()
+                    .map(|k| short_keys([*k], config.modifier_keys()))
+                    .chain(modifiers.iter().map(|m| m.to_string()))
+                    .collect();
+                held.sort();
+                let layer = layers
+                    .entry((held.len(), held.join("+")))
+                    .or_insert_with(|| Layer {
+                        held: input
+                            .iter()
+                            .filter(|k| config.modifier_keys().contains(k))
+                            .cloned()
+                            .chain(modifiers.iter().flat_map(|m| m.keys()))
+                            .map(|k| short_keys([&k], config.modifier_keys()))
+                            .collect(),
+                        ..Default::default()
+                    });
+
+                let label = match map {
+                    Mapping::Remap { output, .. } => short_keys(output, config.modifier_keys()),
+                    _ => "disabled".to_string(),
+                };
+                let name = short_keys(keys.iter().copied(), config.modifier_keys());
+                if keys.len() == 1
+                    && on_diagram.contains(name.as_str())
+                    && map.conditions().is_empty()
+                {
+                    // The first rule for a key in a layer is the one
+                    // that applies
+                    layer.keys.entry(name).or_insert(label);
+                } else {
+                    layer.other.push(format!(
+                        "{} \u{2192} {}{}",
+                        name,
+                        label,
+                        describe_conditions(map.conditions())
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    layers
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_layer_svg(layer: &Layer) -> String {
+    let width = (NAV_OFFSET + 3.) * KEY_UNIT;
+    let height = 6.5 * KEY_UNIT;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+         font-family=\"sans-serif\">\n",
+        width, height
+    );
+
+    for (rows, offset) in [(MAIN_ROWS, 0.), (NAV_ROWS, NAV_OFFSET)] {
+        for (row_idx, row) in rows.iter().enumerate() {
+            // Leave a gap between the function keys and the rest
+            let y = if row_idx == 0 {
+                0.
+            } else {
+                row_idx as f32 + 0.5
+            } * KEY_UNIT;
+            let mut x = offset * KEY_UNIT;
+            for (name, key_width) in row.iter() {
+                let w = key_width * KEY_UNIT;
+                if !name.is_empty() {
+                    let label = layer.keys.get(*name);
+                    let fill = if layer.held.contains(*name) {
+                        "#c8c8c8"
+                    } else if label.is_some() {
+                        "#ffd580"
+                    } else {
+                        "#f4f4f4"
+                    };
+                    svg.push_str(&format!(
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" \
+                         fill=\"{}\" stroke=\"#888\"/>\n",
+                        x + 2.,
+                        y + 2.,
+                        w - 4.,
+                        KEY_UNIT - 4.,
+                        fill
+                    ));
+                    svg.push_str(&format!(
+                        "<text x=\"{}\" y=\"{}\" font-size=\"8\" fill=\"#555\">{}</text>\n",
+                        x + 5.,
+                        y + 12.,
+                        escape_html(name)
+                    ));
+                    if let Some(label) = label {
+                        let lines: Vec<&str> = label.split(['\n', '+']).collect();
+                        for (idx, line) in lines.iter().enumerate() {
+                            svg.push_str(&format!(
+                                "<text x=\"{}\" y=\"{}\" font-size=\"9\" \
+                                 font-weight=\"bold\">{}</text>\n",
+                                x + 5.,
+                                y + 23. + idx as f32 * 9.,
+                                escape_html(line)
+                            ));
+                        }
+                    }
+                }
+                x += w;
+            }
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn write_html(path: &Path, config_path: &Path, config: &MappingConfig) -> Result<()> {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>evremap: {title}</title>\n</head>\n<body>\n\
+         <h1>{title}</h1>\n",
+        title = escape_html(&config_path.display().to_string())
+    );
+
+    for ((_, name), layer) in build_layers(config) {
+        let heading = if name.is_empty() {
+            "Base layer".to_string()
+        } else {
+            format!("While holding {}", name)
+        };
+        html.push_str(&format!("<h2>{}</h2>\n", escape_html(&heading)));
+        html.push_str(&render_layer_svg(&layer));
+        if !layer.other.is_empty() {
+            html.push_str("<ul>\n");
+            for other in &layer.other {
+                html.push_str(&format!("<li>{}</li>\n", escape_html(other)));
+            }
+            html.push_str("</ul>\n");
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+
+    std::fs::write(path, html).context(format!("writing {}", path.display()))
+}
+
+/// Prints the effective mappings from a config, grouped by the key that
+/// triggers them, in the order that the remapper considers them
+fn print_mappings(config: &MappingConfig) {
+    let mappings = config.mappings();
+    println!("Device: {}", config.device());
+    println!("Modifier keys: {}", format_keys(config.modifier_keys()));
+
+    for indicator in config.leds() {
+        println!(
+            "LED: {:?} is lit while {} is held",
+            indicator.led,
+            format_keys(&indicator.keys)
+        );
+    }
+
+    let output = config.output();
+    let mut properties = vec![];
+    if let Some(name) = &output.name {
+        properties.push(format!("name=`{}`", name));
+    }
+    if let Some(phys) = &output.phys {
+        properties.push(format!("phys=`{}`", phys));
+    }
+    for (label, id) in [
+        ("bustype", output.bustype),
+        ("vendor", output.vendor),
+        ("product", output.product),
+    ] {
+        if let Some(id) = id {
+            properties.push(format!("{}=0x{:04x}", label, id));
+        }
+    }
+    if !output.capabilities.is_empty() {
+        let codes: Vec<String> = output.capabilities.iter().map(|c| c.to_string()).collect();
+        properties.push(format!("extra capabilities {}", codes.join(", ")));
+    }
+    if output.split_pointer {
+        match &output.pointer_name {
+            Some(name) => properties.push(format!("pointer events from `{}`", name)),
+            None => properties.push("pointer events from a separate device".to_string()),
+        }
+    }
+    if !properties.is_empty() {
+        println!("Output device: {}", properties.join("; "));
+    }
+
+    if config.tap_timing() != TapTiming::default() {
+        println!(
+            "Taps: keys are held for at least {}ms, with {}ms between events",
+            config.tap_timing().min_press.as_millis(),
+            config.tap_timing().spacing.as_millis()
+        );
+    }
+
+    let dual_roles: Vec<usize> = (0..mappings.len())
+        .filter(|idx| matches!(mappings[*idx], Mapping::DualRole { .. }))
+        .collect();
+    if !dual_roles.is_empty() {
+        println!();
+        println!("Dual role keys, which take precedence over remap rules:");
+        for idx in dual_roles {
+            if let Mapping::DualRole {
+                input,
+                hold,
+                tap,
+                conditions,
+                ..
+            } = &mappings[idx]
+            {
+                println!(
+                    "  {:?}: tap {}, hold {}{}  [{}]",
+                    input,
+                    format_keys(tap),
+                    format_keys(hold),
+                    describe_conditions(conditions),
+                    rule_name(mappings, idx)
+                );
+            }
+        }
+    }
+
+    let disabled: Vec<usize> = (0..mappings.len())
+        .filter(|idx| matches!(mappings[*idx], Mapping::Disable { .. }))
+        .collect();
+    if !disabled.is_empty() {
+        println!();
+        println!("Disabled keys, which are swallowed before remap rules are applied:");
+        for idx in disabled {
+            if let Mapping::Disable {
+                input,
+                modifiers,
+                remove_capability,
+                conditions,
+                ..
+            } = &mappings[idx]
+            {
+                println!(
+                    "  {}{}{}  [{}]",
+                    format_input(input, modifiers),
+                    if *remove_capability {
+                        " (removed from the output device)"
+                    } else {
+                        ""
+                    },
+                    describe_conditions(conditions),
+                    rule_name(mappings, idx)
+                );
+            }
+        }
+    }
+
+    // Group the remap rules by the non-modifier keys that trigger them
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (idx, map) in mappings.iter().enumerate() {
+        if let Mapping::Remap {
+            input, modifiers, ..
+        } = map
+        {
+            let triggers: Vec<String> = input
+                .iter()
+                .filter(|k| !config.modifier_keys().contains(k))
+                .map(|k| format!("{:?}", k))
+                .collect();
+            let triggers = if triggers.is_empty() {
+                vec![format_input(input, modifiers)]
+            } else {
+                triggers
+            };
+            for trigger in triggers {
+                groups.entry(trigger).or_default().push(idx);
+            }
+        }
+    }
+    if groups.is_empty() {
+        return;
+    }
+
+    println!();
+    println!(
+        "Remapped keys; the first rule whose keys are all held, \
+         and whose conditions are met, applies:"
+    );
+    // The rules of each group are in the order of the config, which is
+    // the order that compute_keys applies them in
+    for (trigger, rules) in groups {
+        println!("  {}", trigger);
+        for idx in rules {
+            if let Mapping::Remap {
+                input,
+                modifiers,
+                output,
+                modifier_options,
+                conditions,
+                ..
+            } = &mappings[idx]
+            {
+                let output = if output.is_empty() {
+                    "(nothing)".to_string()
+                } else {
+                    format_keys(output)
+                };
+                println!(
+                    "    {} -> {}{}{}  [{}]",
+                    format_input(input, modifiers),
+                    output,
+                    describe_options(modifier_options),
+                    describe_conditions(conditions),
+                    rule_name(mappings, idx)
+                );
+            }
+        }
+    }
+}
+
+pub fn explain_config(path: &Path, html: Option<&Path>) -> Result<()> {
+    let mapping_config = MappingConfig::from_file(path)
+        .context(format!("loading MappingConfig from {}", path.display()))?;
+
+    print_mappings(&mapping_config);
+
+    if let Some(html) = html {
+        write_html(html, path, &mapping_config)?;
+        log::info!("Wrote keyboard diagram to {}", html.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,673 @@
+//! A privileged helper that opens input devices and creates uinput
+//! devices on behalf of an unprivileged `evremap remap`, so that the
+//! user running the remapper doesn't need access to every keyboard.
+//!
+//! The helper listens on a unix socket.  Each request and response is
+//! a line of JSON, and the fds of the devices are passed alongside the
+//! responses.  The devices that a client opens stay grabbed, and the
+//! output devices that it creates exist, until it disconnects.
+//!
+//! Each client may only use the devices that the administrator allowed
+//! its uid.  The output devices are derived from the devices that the
+//! client opened, rather than described by the client, so that it can't
+//! create a device unlike them.
+use crate::deviceinfo::{open_device, DeviceInfo, DeviceMatch, VIRTUAL_DEVICE_NAME_PREFIX};
+use crate::mapping::KeyCode;
+use crate::output::{DeviceSpec, SinkKind};
+use anyhow::{anyhow, bail, Context, Result};
+use evdev_rs::enums::{int_to_ev_key, EventCode};
+use evdev_rs::{Device, GrabMode, UInputDevice};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The most clients that may be connected at once.  Each client has
+/// a thread of its own, and holds on to the devices that it opened.
+const MAX_CLIENTS: usize = 16;
+
+/// The most output devices that a client may create for each device
+/// that it opened: one, or two when the pointer events are split out
+const MAX_OUTPUTS_PER_DEVICE: usize = 2;
+
+/// The most keys that a client may add to an output device, beyond
+/// those of the device that it is created for
+const MAX_ADDED_KEYS: usize = 128;
+
+/// The longest message that may be sent over the socket
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    ListDevices,
+    /// Open and grab the input device at `path`
+    OpenDevice {
+        path: PathBuf,
+    },
+    /// Create an output device for the input device at `source`,
+    /// which must have been opened by the same client.  It has those
+    /// capabilities of the input device that `kind` accepts, along with
+    /// the keys in `add` and without those in `remove`.
+    CreateOutput {
+        source: PathBuf,
+        kind: SinkKind,
+        add: Vec<u32>,
+        remove: Vec<u32>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Devices(Vec<DeviceInfo>),
+    /// The fd of the device accompanies this response
+    Opened,
+    /// The output device would have had no capabilities, so it
+    /// wasn't created
+    NoOutput,
+    Error(String),
+}
+
+/// Sends a message, along with `fd` if there is one
+fn send_message<T: Serialize>(stream: &UnixStream, message: &T, fd: Option<RawFd>) -> Result<()> {
+    let mut data = serde_json::to_vec(message)?;
+    data.push(b'\n');
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        let fd_size = std::mem::size_of::<RawFd>() as u32;
+        unsafe {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fd_size) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fd_size) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+    }
+
+    let sent = loop {
+        let res = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+        if res >= 0 {
+            break res as usize;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("sending to the helper socket");
+        }
+    };
+    // The fd went with the first part; send whatever is left
+    (&*stream).write_all(&data[sent..])?;
+    Ok(())
+}
+
+/// Receives a message, along with the fd that was passed with it.
+/// Returns None if the other end disconnected.
+fn recv_message<T: DeserializeOwned>(stream: &UnixStream) -> Result<Option<(T, Option<File>)>> {
+    let mut data = vec![];
+    let mut file = None;
+    while !data.ends_with(b"\n") {
+        let mut buf = [0u8; 4096];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let res = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err).context("receiving from the helper socket");
+        }
+        if res == 0 {
+            if data.is_empty() {
+                return Ok(None);
+            }
+            bail!("the helper socket was closed part way through a message");
+        }
+
+        // Take ownership of every fd that was passed, so that those
+        // that we reject are closed rather than leaked
+        let mut files: Vec<File> = file.take().into_iter().collect();
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let fds = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    for i in 0..len / std::mem::size_of::<RawFd>() {
+                        files.push(File::from_raw_fd(std::ptr::read_unaligned(fds.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            bail!("the control data of a message on the helper socket was truncated");
+        }
+        if files.len() > 1 {
+            bail!("more than one fd was passed with a message on the helper socket");
+        }
+        file = files.pop();
+        data.extend_from_slice(&buf[..res as usize]);
+        if data.len() > MAX_MESSAGE_LEN {
+            bail!(
+                "a message on the helper socket is longer than {} bytes",
+                MAX_MESSAGE_LEN
+            );
+        }
+    }
+
+    Ok(Some((serde_json::from_slice(&data)?, file)))
+}
+
+/// Returns a new fd that refers to the same open file as `fd`, and
+/// so shares its grab
+fn dup_fd(fd: RawFd) -> Result<File> {
+    let res = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error()).context("duplicating fd");
+    }
+    Ok(unsafe { File::from_raw_fd(res) })
+}
+
+/// Returns the uid of the process at the other end of `stream`
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error()).context("getting the peer credentials");
+    }
+    Ok(cred.uid)
+}
+
+/// A connection to an `evremap helper`
+pub(crate) struct HelperConnection {
+    stream: UnixStream,
+}
+
+impl HelperConnection {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).context(format!(
+            "connecting to evremap helper at {}",
+            socket.display()
+        ))?;
+        Ok(Self { stream })
+    }
+
+    fn call(&self, request: &Request) -> Result<(Response, Option<File>)> {
+        send_message(&self.stream, request, None)?;
+        match recv_message(&self.stream)? {
+            Some((Response::Error(err), _)) => bail!("evremap helper: {}", err),
+            Some(response) => Ok(response),
+            None => bail!("evremap helper closed the connection"),
+        }
+    }
+
+    pub fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        match self.call(&Request::ListDevices)? {
+            (Response::Devices(devices), _) => Ok(devices),
+            (response, _) => bail!("unexpected response from evremap helper: {:?}", response),
+        }
+    }
+
+    /// Opens and grabs the input device at `path`
+    pub fn open_device(&self, path: &Path) -> Result<File> {
+        let request = Request::OpenDevice {
+            path: path.to_path_buf(),
+        };
+        match self.call(&request)? {
+            (Response::Opened, Some(file)) => Ok(file),
+            (response, _) => bail!("unexpected response from evremap helper: {:?}", response),
+        }
+    }
+
+    /// Creates a uinput device for the input device at `source`, which
+    /// must have been opened by `open_device`, returning its fd.  Returns
+    /// None if the device would have had no capabilities.
+    pub fn create_output(
+        &self,
+        source: &Path,
+        kind: SinkKind,
+        add: &HashSet<KeyCode>,
+        remove: &[KeyCode],
+    ) -> Result<Option<File>> {
+        let request = Request::CreateOutput {
+            source: source.to_path_buf(),
+            kind,
+            add: add.iter().map(|key| key.clone() as u32).collect(),
+            remove: remove.iter().map(|key| key.clone() as u32).collect(),
+        };
+        match self.call(&request)? {
+            (Response::Opened, Some(file)) => Ok(Some(file)),
+            (Response::NoOutput, _) => Ok(None),
+            (response, _) => bail!("unexpected response from evremap helper: {:?}", response),
+        }
+    }
+}
+
+/// Asks the helper listening on `socket` for the list of input devices
+pub fn list_devices(socket: &Path) -> Result<Vec<DeviceInfo>> {
+    HelperConnection::connect(socket)?.list_devices()
+}
+
+/// Allows a user to open the input devices that match `device`.
+/// This is parsed from `USER:DEVICE`, where USER is a user name or uid,
+/// and DEVICE is either the path of a device node, or a symlink to one,
+/// or a device name, which may contain `*` and `?` wildcards.
+#[derive(Debug, Clone)]
+pub struct AllowRule {
+    uid: libc::uid_t,
+    device: DeviceMatch,
+}
+
+impl std::str::FromStr for AllowRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, device) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("`{}` should be USER:DEVICE", s))?;
+        let uid = match user.parse() {
+            Ok(uid) => uid,
+            Err(_) => {
+                let name = CString::new(user)?;
+                let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+                if entry.is_null() {
+                    bail!("there is no user named `{}`", user);
+                }
+                unsafe { (*entry).pw_uid }
+            }
+        };
+        let device = if device.starts_with('/') {
+            DeviceMatch {
+                path: Some(device.into()),
+                ..Default::default()
+            }
+        } else {
+            DeviceMatch::by_name(device)
+        };
+        Ok(Self { uid, device })
+    }
+}
+
+/// An input device that a client opened
+struct Granted {
+    path: PathBuf,
+    /// Shares the grab of the fd that was passed to the client, and
+    /// is used to find the capabilities of its output devices
+    file: File,
+    outputs: usize,
+}
+
+/// Returns true for the keys that a client may add to an output device:
+/// keyboard keys and mouse buttons, but not the buttons of joysticks,
+/// gamepads or tablets
+fn may_add_key(key: &KeyCode) -> bool {
+    let code = key.clone() as u32;
+    let within = |first: KeyCode, last: KeyCode| (first as u32..=last as u32).contains(&code);
+    within(KeyCode::BTN_LEFT, KeyCode::BTN_TASK)
+        || !(within(KeyCode::BTN_0, KeyCode::BTN_GEAR_UP)
+            || within(KeyCode::BTN_DPAD_UP, KeyCode::BTN_DPAD_RIGHT)
+            || within(KeyCode::BTN_TRIGGER_HAPPY1, KeyCode::BTN_TRIGGER_HAPPY40))
+}
+
+/// The state of a connected client
+struct Client {
+    uid: libc::uid_t,
+    /// The devices that the client is allowed to open
+    allowed: Vec<DeviceMatch>,
+    granted: Vec<Granted>,
+    /// Dropping a UInputDevice would destroy the device, so they
+    /// are kept until the client disconnects
+    outputs: Vec<UInputDevice>,
+}
+
+impl Client {
+    fn is_allowed(&self, info: &DeviceInfo) -> bool {
+        // DeviceMatch never matches our own output devices
+        self.allowed.iter().any(|device| device.matches(info))
+    }
+
+    fn handle_request(&mut self, request: Request) -> Result<(Response, Option<File>)> {
+        if self.allowed.is_empty() {
+            bail!("uid {} isn't allowed to use any devices", self.uid);
+        }
+        match request {
+            Request::ListDevices => {
+                let devices = DeviceInfo::obtain_device_list()?
+                    .into_iter()
+                    .filter(|info| self.is_allowed(info))
+                    .collect();
+                Ok((Response::Devices(devices), None))
+            }
+            Request::OpenDevice { path } => {
+                let info = DeviceInfo::obtain_device_list()?
+                    .into_iter()
+                    .find(|info| info.path == path)
+                    .ok_or_else(|| anyhow!("{} is not an input device", path.display()))?;
+                if !self.is_allowed(&info) {
+                    bail!(
+                        "uid {} isn't allowed to use {} ({})",
+                        self.uid,
+                        path.display(),
+                        info.name
+                    );
+                }
+
+                let mut input = open_device(&path, true)?;
+                input
+                    .grab(GrabMode::Grab)
+                    .context(format!("grabbing exclusive access on {}", path.display()))?;
+                let fd = input
+                    .fd()
+                    .map(IntoRawFd::into_raw_fd)
+                    .ok_or_else(|| anyhow!("{} has no fd", path.display()))?;
+                let file = dup_fd(fd)?;
+                log::info!(
+                    "Opened {} ({}) for uid {}",
+                    info.name,
+                    path.display(),
+                    self.uid
+                );
+                self.granted.push(Granted {
+                    path,
+                    file: dup_fd(fd)?,
+                    outputs: 0,
+                });
+                Ok((Response::Opened, Some(file)))
+            }
+            Request::CreateOutput {
+                source,
+                kind,
+                add,
+                remove,
+            } => {
+                let granted = self
+                    .granted
+                    .iter_mut()
+                    .find(|granted| granted.path == source)
+                    .ok_or_else(|| {
+                        anyhow!("{} hasn't been opened by this client", source.display())
+                    })?;
+                if granted.outputs >= MAX_OUTPUTS_PER_DEVICE {
+                    bail!(
+                        "{} already has {} output devices",
+                        source.display(),
+                        MAX_OUTPUTS_PER_DEVICE
+                    );
+                }
+
+                if add.len() > MAX_ADDED_KEYS {
+                    bail!(
+                        "at most {} keys may be added to an output device",
+                        MAX_ADDED_KEYS
+                    );
+                }
+
+                let input = Device::new_from_fd(granted.file.try_clone()?)
+                    .context(format!("reading the capabilities of {}", source.display()))?;
+                for code in add {
+                    let key = int_to_ev_key(code).filter(may_add_key).ok_or_else(|| {
+                        anyhow!("key {} can't be added to an output device", code)
+                    })?;
+                    input
+                        .enable(&EventCode::EV_KEY(key.clone()))
+                        .context(format!("enable key {:?}", key))?;
+                }
+                for key in remove.into_iter().filter_map(int_to_ev_key) {
+                    input
+                        .disable(&EventCode::EV_KEY(key.clone()))
+                        .context(format!("disable key {:?}", key))?;
+                }
+                // The client doesn't get to choose the name either;
+                // DeviceSpec::create tags the phys
+                let mut name = format!("{} for {}", VIRTUAL_DEVICE_NAME_PREFIX, source.display());
+                if kind == SinkKind::Pointer {
+                    name.push_str(" pointer");
+                }
+                let spec = match DeviceSpec::from_device(&input, kind, &name)? {
+                    Some(spec) => spec,
+                    None => return Ok((Response::NoOutput, None)),
+                };
+
+                let output = spec.create().context("creating UInputDevice")?;
+                let fd = output
+                    .fd()
+                    .map(IntoRawFd::into_raw_fd)
+                    .ok_or_else(|| anyhow!("UInputDevice has no fd"))?;
+                let file = dup_fd(fd)?;
+                log::info!(
+                    "Created {} for {} for uid {}",
+                    output.devnode().unwrap_or("an output device"),
+                    source.display(),
+                    self.uid
+                );
+                granted.outputs += 1;
+                self.outputs.push(output);
+                Ok((Response::Opened, Some(file)))
+            }
+        }
+    }
+}
+
+fn serve_client(stream: &UnixStream, rules: &[AllowRule]) -> Result<()> {
+    let uid = peer_uid(stream)?;
+    log::info!("Client with uid {} connected", uid);
+    let mut client = Client {
+        uid,
+        allowed: rules
+            .iter()
+            .filter(|rule| rule.uid == uid)
+            .map(|rule| rule.device.clone())
+            .collect(),
+        granted: vec![],
+        outputs: vec![],
+    };
+    while let Some((request, _)) = recv_message::<Request>(stream)? {
+        log::debug!("Request from uid {}: {:?}", uid, request);
+        let (response, file) = match client.handle_request(request) {
+            Ok(response) => response,
+            Err(err) => (Response::Error(format!("{:#}", err)), None),
+        };
+        send_message(stream, &response, file.as_ref().map(AsRawFd::as_raw_fd))?;
+    }
+    log::info!("Client with uid {} disconnected", uid);
+    Ok(())
+}
+
+/// Runs the helper, listening on `socket`.  The socket is accessible
+/// to the members of `group`, or only to root if there is no group.
+/// Each client may only use the devices that `rules` allow its uid.
+pub fn run_helper(socket: &Path, group: Option<&str>, rules: Vec<AllowRule>) -> Result<()> {
+    // A socket left behind by a previous run would prevent binding
+    if socket.exists() {
+        std::fs::remove_file(socket).context(format!("removing {}", socket.display()))?;
+    }
+    // Create the socket accessible only to us, so that nobody can
+    // connect before its permissions are set below
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    unsafe { libc::umask(umask) };
+    let listener = listener.context(format!("binding {}", socket.display()))?;
+
+    let mode = match group {
+        Some(group) => {
+            let name = CString::new(group)?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                bail!("there is no group named `{}`", group);
+            }
+            let gid = unsafe { (*entry).gr_gid };
+            std::os::unix::fs::chown(socket, None, Some(gid))
+                .context(format!("changing the group of {}", socket.display()))?;
+            0o660
+        }
+        None => 0o600,
+    };
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(mode))
+        .context(format!("changing the permissions of {}", socket.display()))?;
+
+    if rules.is_empty() {
+        log::warn!("No --allow rules were given, so every request will be rejected");
+    }
+    let rules = Arc::new(rules);
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    log::info!("Listening on {}", socket.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Accepting a connection: {}", err);
+                continue;
+            }
+        };
+        if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            log::warn!(
+                "Rejecting a connection because {} clients are connected",
+                MAX_CLIENTS
+            );
+            continue;
+        }
+        let rules = Arc::clone(&rules);
+        let clients = Arc::clone(&clients);
+        std::thread::spawn(move || {
+            if let Err(err) = serve_client(&stream, &rules) {
+                log::error!("{:#}", err);
+            }
+            clients.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_allow_rules() {
+        let rule: AllowRule = "1000:/dev/input/by-id/usb-keyboard".parse().unwrap();
+        assert_eq!(rule.uid, 1000);
+        assert_eq!(
+            rule.device.path.as_deref(),
+            Some(Path::new("/dev/input/by-id/usb-keyboard"))
+        );
+
+        let rule: AllowRule = "root:AT Translated Set 2 keyboard".parse().unwrap();
+        assert_eq!(rule.uid, 0);
+        assert!(rule
+            .device
+            .name
+            .unwrap()
+            .matches("AT Translated Set 2 keyboard"));
+
+        assert!("1000".parse::<AllowRule>().is_err());
+        assert!("no-such-user-here:*".parse::<AllowRule>().is_err());
+    }
+
+    #[test]
+    fn pass_fd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let file = File::open("/dev/null").unwrap();
+        send_message(&a, &Response::Opened, Some(file.as_raw_fd())).unwrap();
+        let (response, file) = recv_message::<Response>(&b).unwrap().unwrap();
+        assert!(matches!(response, Response::Opened));
+        assert!(file.is_some());
+    }
+
+    #[test]
+    fn keys_that_may_be_added() {
+        assert!(may_add_key(&KeyCode::KEY_A));
+        assert!(may_add_key(&KeyCode::KEY_F24));
+        assert!(may_add_key(&KeyCode::KEY_BRIGHTNESS_MIN));
+        assert!(may_add_key(&KeyCode::BTN_LEFT));
+        assert!(may_add_key(&KeyCode::BTN_TASK));
+        assert!(!may_add_key(&KeyCode::BTN_0));
+        assert!(!may_add_key(&KeyCode::BTN_SOUTH));
+        assert!(!may_add_key(&KeyCode::BTN_TOUCH));
+        assert!(!may_add_key(&KeyCode::BTN_DPAD_UP));
+        assert!(!may_add_key(&KeyCode::BTN_TRIGGER_HAPPY1));
+    }
+
+    #[test]
+    fn reject_long_messages() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let writer = std::thread::spawn(move || {
+            // The write fails once the reader gives up and closes its end
+            let _ = (&a).write_all(&vec![b'x'; MAX_MESSAGE_LEN * 2]);
+        });
+        let err = recv_message::<Response>(&b).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+        drop(b);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn reject_several_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let files = [
+            File::open("/dev/null").unwrap(),
+            File::open("/dev/null").unwrap(),
+        ];
+        let fds = [files[0].as_raw_fd(), files[1].as_raw_fd()];
+        let mut data = b"\"Opened\"\n".to_vec();
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut control = [0u64; 8];
+        let fds_size = std::mem::size_of_val(&fds) as u32;
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        unsafe {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fds_size) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut [RawFd; 2], fds);
+            assert!(libc::sendmsg(a.as_raw_fd(), &msg, 0) > 0);
+        }
+
+        let err = recv_message::<Response>(&b).unwrap_err();
+        assert!(err.to_string().contains("more than one fd"), "{}", err);
+    }
+}
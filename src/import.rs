@@ -0,0 +1,599 @@
+use anyhow::{bail, Context, Result};
+use evremap::mapping::{default_modifier_keys, parse_key};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The configuration formats that can be converted into an evremap config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Keyd,
+    XkbOptions,
+    InterceptionDualFunctionKeys,
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keyd" => Ok(Self::Keyd),
+            "xkb-options" => Ok(Self::XkbOptions),
+            "interception-dual-function-keys" => Ok(Self::InterceptionDualFunctionKeys),
+            _ => bail!(
+                "invalid format `{}`; expected `keyd`, `xkb-options` or \
+                 `interception-dual-function-keys`",
+                s
+            ),
+        }
+    }
+}
+
+/// The rules that were converted from another format.
+/// Keys are held using the names that appear in evremap configs.
+#[derive(Default)]
+struct Imported {
+    /// Top level lines that select the device
+    device: Vec<String>,
+    dual_role: Vec<(String, Vec<String>, Vec<String>)>,
+    disable: Vec<Vec<String>>,
+    remap: Vec<(Vec<String>, Vec<String>)>,
+    /// Descriptions of things that could not be converted
+    unsupported: Vec<String>,
+}
+
+fn quote_list(keys: &[String]) -> String {
+    let keys: Vec<String> = keys.iter().map(|k| format!("\"{}\"", k)).collect();
+    format!("[{}]", keys.join(", "))
+}
+
+impl Imported {
+    fn to_toml(&self, source: &Path) -> String {
+        let mut toml = format!("# Imported from {}\n", source.display());
+        for item in &self.unsupported {
+            toml.push_str(&format!("# Not converted: {}\n", item));
+        }
+        toml.push('\n');
+
+        if self.device.is_empty() {
+            toml.push_str(
+                "# Run `sudo evremap list-devices` and set the name \
+                 of your keyboard here\n",
+            );
+            toml.push_str("device_name = \"\"\n");
+        }
+        for line in &self.device {
+            toml.push_str(line);
+            toml.push('\n');
+        }
+
+        for (input, hold, tap) in &self.dual_role {
+            toml.push_str(&format!(
+                "\n[[dual_role]]\ninput = \"{}\"\nhold = {}\ntap = {}\n",
+                input,
+                quote_list(hold),
+                quote_list(tap)
+            ));
+        }
+        for input in &self.disable {
+            toml.push_str(&format!("\n[[disable]]\ninput = {}\n", quote_list(input)));
+        }
+        for (input, output) in &self.remap {
+            toml.push_str(&format!(
+                "\n[[remap]]\ninput = {}\noutput = {}\n",
+                quote_list(input),
+                quote_list(output)
+            ));
+        }
+        toml
+    }
+
+    /// Swaps each pair of keys for an xkb `option`.  The rules for the
+    /// two directions would undo each other while both keys are held,
+    /// so they are preceded by a rule that passes the pair through.
+    /// That only works because the pair is then hidden from the later
+    /// rules, which doesn't happen for modifiers, so keys that are
+    /// modifiers can't be swapped.
+    fn swap(&mut self, option: &str, pairs: &[(&str, &str)]) {
+        let modifiers = default_modifier_keys();
+        let is_modifier = |key: &str| parse_key(key).is_some_and(|k| modifiers.contains(&k));
+        if pairs.iter().any(|(a, b)| is_modifier(a) || is_modifier(b)) {
+            self.unsupported.push(format!(
+                "xkb option {} (swapping a modifier key can't be expressed with remap rules)",
+                option
+            ));
+            return;
+        }
+        for (a, b) in pairs {
+            let both = vec![a.to_string(), b.to_string()];
+            self.remap.push((both.clone(), both));
+            self.map(a, b);
+            self.map(b, a);
+        }
+    }
+
+    fn map(&mut self, input: &str, output: &str) {
+        self.remap
+            .push((vec![input.to_string()], vec![output.to_string()]));
+    }
+}
+
+/// Converts a key name used by keyd into an evremap key name
+fn keyd_key(name: &str) -> Option<String> {
+    let name = match name {
+        "control" | "leftcontrol" => "KEY_LEFTCTRL",
+        "rightcontrol" => "KEY_RIGHTCTRL",
+        "shift" => "KEY_LEFTSHIFT",
+        "alt" => "KEY_LEFTALT",
+        "altgr" => "KEY_RIGHTALT",
+        "meta" => "KEY_LEFTMETA",
+        "escape" => "KEY_ESC",
+        "pageup" => "KEY_PAGEUP",
+        "pagedown" => "KEY_PAGEDOWN",
+        name => name,
+    };
+    parse_key(name).map(|code| format!("{:?}", code))
+}
+
+/// Parses a keyd device id, such as `046d:c52b`, into its vendor and
+/// product.  Ids that exclude a device, such as `-046d:c52b`, and those
+/// that only apply to keyboards or mice, such as `k:046d:c52b`, aren't
+/// plain ids and are rejected.
+fn keyd_id(id: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = id.split_once(':')?;
+    let parse = |half: &str| {
+        if half.len() == 4 && half.chars().all(|c| c.is_ascii_hexdigit()) {
+            u16::from_str_radix(half, 16).ok()
+        } else {
+            None
+        }
+    };
+    Some((parse(vendor)?, parse(product)?))
+}
+
+/// Converts a keyd modifier layer name, such as `control` or
+/// `control+alt`, into side agnostic evremap modifiers
+fn keyd_layer_modifiers(layer: &str) -> Option<Vec<String>> {
+    layer
+        .split('+')
+        .map(|m| match m {
+            "control" => Some("CTRL".to_string()),
+            "shift" => Some("SHIFT".to_string()),
+            "alt" => Some("ALT".to_string()),
+            "meta" => Some("META".to_string()),
+            "altgr" => Some("KEY_RIGHTALT".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Converts a keyd key expression, such as `esc` or `C-S-t`, into
+/// the list of keys that it produces
+fn keyd_keys(expr: &str) -> Option<Vec<String>> {
+    let mut keys = vec![];
+    let mut rest = expr;
+    loop {
+        let modifier = match rest.get(..2) {
+            Some("C-") => "KEY_LEFTCTRL",
+            Some("S-") => "KEY_LEFTSHIFT",
+            Some("A-") => "KEY_LEFTALT",
+            Some("M-") => "KEY_LEFTMETA",
+            Some("G-") => "KEY_RIGHTALT",
+            _ => break,
+        };
+        keys.push(modifier.to_string());
+        rest = &rest[2..];
+    }
+    keys.push(keyd_key(rest)?);
+    Some(keys)
+}
+
+fn import_keyd(data: &str) -> Imported {
+    let mut imported = Imported::default();
+    let mut section = String::new();
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_string();
+            continue;
+        }
+
+        if section == "ids" {
+            if line == "*" {
+                imported
+                    .device
+                    .push("match = \"all-keyboards\"".to_string());
+            } else if let Some((vendor, product)) = keyd_id(line) {
+                if imported.device.is_empty() {
+                    imported.device.push(format!("vendor = 0x{:04x}", vendor));
+                    imported.device.push(format!("product = 0x{:04x}", product));
+                } else {
+                    imported
+                        .unsupported
+                        .push(format!("additional device id {}", line));
+                }
+            } else {
+                imported.unsupported.push(format!("[ids] {}", line));
+            }
+            continue;
+        }
+
+        let (key, action) = match line.split_once('=') {
+            Some((key, action)) => (key.trim(), action.trim()),
+            None => {
+                imported.unsupported.push(format!("[{}] {}", section, line));
+                continue;
+            }
+        };
+        let input = match keyd_key(key) {
+            Some(input) => input,
+            None => {
+                imported.unsupported.push(format!("[{}] {}", section, line));
+                continue;
+            }
+        };
+
+        if section == "main" {
+            // overload(layer, tap) holds the layer, which must be a
+            // modifier for us to convert it, and taps the other key
+            if let Some(args) = action
+                .strip_prefix("overload(")
+                .and_then(|a| a.strip_suffix(')'))
+            {
+                if let Some((layer, tap)) = args.split_once(',') {
+                    let hold = keyd_layer_modifiers(layer.trim())
+                        .and(layer.trim().split('+').map(keyd_key).collect());
+                    if let (Some(hold), Some(tap)) = (hold, keyd_keys(tap.trim())) {
+                        imported.dual_role.push((input, hold, tap));
+                        continue;
+                    }
+                }
+            } else if let Some(output) = keyd_keys(action) {
+                imported.remap.push((vec![input], output));
+                continue;
+            }
+        } else if let Some(mut modifiers) = keyd_layer_modifiers(&section) {
+            // A modifier layer applies while the modifiers are held
+            if let Some(output) = keyd_keys(action) {
+                modifiers.push(input);
+                imported.remap.push((modifiers, output));
+                continue;
+            }
+        }
+
+        imported.unsupported.push(format!("[{}] {}", section, line));
+    }
+
+    imported
+}
+
+/// Converts xkb options, such as `ctrl:nocaps,altwin:swap_alt_win`.
+/// The data may also be an `/etc/default/keyboard` file, in which case
+/// the options are taken from its `XKBOPTIONS` line.
+fn import_xkb_options(data: &str) -> Imported {
+    let mut imported = Imported::default();
+
+    let options = data
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("XKBOPTIONS="))
+        .map(|opts| opts.trim_matches('"').to_string())
+        .unwrap_or_else(|| data.to_string());
+
+    for option in options.split(|c: char| c == ',' || c.is_whitespace()) {
+        match option {
+            "" => {}
+            "ctrl:nocaps" | "caps:ctrl_modifier" => imported.map("KEY_CAPSLOCK", "KEY_LEFTCTRL"),
+            "ctrl:swapcaps" => imported.swap(option, &[("KEY_CAPSLOCK", "KEY_LEFTCTRL")]),
+            "ctrl:swap_lalt_lctl" => imported.swap(option, &[("KEY_LEFTALT", "KEY_LEFTCTRL")]),
+            "ctrl:swap_lwin_lctl" => imported.swap(option, &[("KEY_LEFTMETA", "KEY_LEFTCTRL")]),
+            "ctrl:ralt_rctrl" => imported.map("KEY_RIGHTALT", "KEY_RIGHTCTRL"),
+            "ctrl:rctrl_ralt" => imported.map("KEY_RIGHTCTRL", "KEY_RIGHTALT"),
+            "caps:escape" => imported.map("KEY_CAPSLOCK", "KEY_ESC"),
+            "caps:swapescape" => imported.swap(option, &[("KEY_CAPSLOCK", "KEY_ESC")]),
+            "caps:backspace" => imported.map("KEY_CAPSLOCK", "KEY_BACKSPACE"),
+            "caps:super" => imported.map("KEY_CAPSLOCK", "KEY_LEFTMETA"),
+            "caps:none" => imported.disable.push(vec!["KEY_CAPSLOCK".to_string()]),
+            "altwin:swap_alt_win" => imported.swap(
+                option,
+                &[
+                    ("KEY_LEFTALT", "KEY_LEFTMETA"),
+                    ("KEY_RIGHTALT", "KEY_RIGHTMETA"),
+                ],
+            ),
+            "altwin:swap_lalt_lwin" => imported.swap(option, &[("KEY_LEFTALT", "KEY_LEFTMETA")]),
+            "altwin:ctrl_win" => {
+                imported.map("KEY_LEFTMETA", "KEY_LEFTCTRL");
+                imported.map("KEY_RIGHTMETA", "KEY_RIGHTCTRL");
+            }
+            option => imported.unsupported.push(format!("xkb option {}", option)),
+        }
+    }
+
+    imported
+}
+
+/// A key, or list of keys, in an interception-tools
+/// dual-function-keys config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DualFunctionKeys {
+    Key(String),
+    Keys(Vec<String>),
+}
+
+impl DualFunctionKeys {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::Key(key) => vec![key],
+            Self::Keys(keys) => keys,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct DualFunctionMapping {
+    key: String,
+    tap: DualFunctionKeys,
+    hold: DualFunctionKeys,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct DualFunctionConfig {
+    /// The timeouts, such as `TAP_MILLISEC`, after which a key counts
+    /// as held.  evremap decides by whether another key was pressed
+    /// instead, so these can't be converted.
+    #[serde(default)]
+    timing: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    mappings: Vec<DualFunctionMapping>,
+}
+
+fn import_interception_dual_function_keys(data: &str) -> Result<Imported> {
+    let config: DualFunctionConfig = serde_yaml::from_str(data)?;
+    let mut imported = Imported::default();
+    for name in config.timing.keys() {
+        imported.unsupported.push(format!(
+            "TIMING {} (dual role keys are tapped when no other key is pressed while they are held)",
+            name
+        ));
+    }
+    for mapping in config.mappings {
+        let hold = mapping.hold.into_vec();
+        let tap = mapping.tap.into_vec();
+        let invalid: Vec<&String> = std::iter::once(&mapping.key)
+            .chain(&hold)
+            .chain(&tap)
+            .filter(|key| parse_key(key).is_none())
+            .collect();
+        if !invalid.is_empty() {
+            let invalid: Vec<String> = invalid.iter().map(|k| format!("`{}`", k)).collect();
+            imported.unsupported.push(format!(
+                "mapping for {} (unknown key {})",
+                mapping.key,
+                invalid.join(", ")
+            ));
+            continue;
+        }
+        imported.dual_role.push((mapping.key, hold, tap));
+    }
+    Ok(imported)
+}
+
+pub fn import_config(format: ImportFormat, path: &Path) -> Result<()> {
+    let data = std::fs::read_to_string(path).context(format!("reading {}", path.display()))?;
+    let imported = match format {
+        ImportFormat::Keyd => import_keyd(&data),
+        ImportFormat::XkbOptions => import_xkb_options(&data),
+        ImportFormat::InterceptionDualFunctionKeys => import_interception_dual_function_keys(&data)
+            .context(format!("parsing {}", path.display()))?,
+    };
+
+    for item in &imported.unsupported {
+        log::warn!("Not converted: {}", item);
+    }
+    print!("{}", imported.to_toml(path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn keyd() {
+        let imported = import_keyd(
+            "[ids]
+046d:c52b
+
+[main]
+capslock = overload(control, esc)
+insert = C-S-v
+rightalt = layer(nav)
+
+[alt]
+h = left
+",
+        );
+        assert_eq!(imported.device, vec!["vendor = 0x046d", "product = 0xc52b"]);
+        assert_eq!(
+            imported.dual_role,
+            vec![(
+                "KEY_CAPSLOCK".to_string(),
+                keys(&["KEY_LEFTCTRL"]),
+                keys(&["KEY_ESC"])
+            )]
+        );
+        assert_eq!(
+            imported.remap,
+            vec![
+                (
+                    keys(&["KEY_INSERT"]),
+                    keys(&["KEY_LEFTCTRL", "KEY_LEFTSHIFT", "KEY_V"])
+                ),
+                (keys(&["ALT", "KEY_H"]), keys(&["KEY_LEFT"])),
+            ]
+        );
+        assert_eq!(imported.unsupported, vec!["[main] rightalt = layer(nav)"]);
+    }
+
+    #[test]
+    fn keyd_ids() {
+        assert_eq!(keyd_id("046d:c52b"), Some((0x046d, 0xc52b)));
+        assert_eq!(keyd_id("-046d:c52b"), None);
+        assert_eq!(keyd_id("k:046d:c52b"), None);
+        assert_eq!(keyd_id("m:046d:c52b"), None);
+        assert_eq!(keyd_id("46d:c52b"), None);
+
+        let imported = import_keyd("[ids]\n-046d:c52b\nk:1234:5678\n04D9:0169\n");
+        assert_eq!(imported.device, vec!["vendor = 0x04d9", "product = 0x0169"]);
+        assert_eq!(
+            imported.unsupported,
+            vec!["[ids] -046d:c52b", "[ids] k:1234:5678"]
+        );
+    }
+
+    #[test]
+    fn keyd_all_keyboards() {
+        let imported = import_keyd("[ids]\n*\n\n[main]\nesc = capslock\n");
+        assert_eq!(imported.device, vec!["match = \"all-keyboards\""]);
+        assert_eq!(
+            imported.remap,
+            vec![(keys(&["KEY_ESC"]), keys(&["KEY_CAPSLOCK"]))]
+        );
+    }
+
+    #[test]
+    fn xkb_options() {
+        let imported = import_xkb_options("ctrl:nocaps,caps:swapescape,compose:ralt");
+        assert_eq!(
+            imported.remap,
+            vec![
+                (keys(&["KEY_CAPSLOCK"]), keys(&["KEY_LEFTCTRL"])),
+                (
+                    keys(&["KEY_CAPSLOCK", "KEY_ESC"]),
+                    keys(&["KEY_CAPSLOCK", "KEY_ESC"])
+                ),
+                (keys(&["KEY_CAPSLOCK"]), keys(&["KEY_ESC"])),
+                (keys(&["KEY_ESC"]), keys(&["KEY_CAPSLOCK"])),
+            ]
+        );
+        assert_eq!(imported.unsupported, vec!["xkb option compose:ralt"]);
+    }
+
+    #[test]
+    fn xkb_options_from_keyboard_file() {
+        let imported = import_xkb_options(
+            "XKBMODEL=\"pc105\"\nXKBLAYOUT=\"us\"\nXKBOPTIONS=\"caps:escape\"\n",
+        );
+        assert_eq!(
+            imported.remap,
+            vec![(keys(&["KEY_CAPSLOCK"]), keys(&["KEY_ESC"]))]
+        );
+        assert!(imported.unsupported.is_empty());
+    }
+
+    #[test]
+    fn xkb_caps_none() {
+        let imported = import_xkb_options("caps:none");
+        assert!(imported.remap.is_empty());
+        assert_eq!(imported.disable, vec![keys(&["KEY_CAPSLOCK"])]);
+        assert!(imported
+            .to_toml(Path::new("keyboard"))
+            .ends_with("\n[[disable]]\ninput = [\"KEY_CAPSLOCK\"]\n"));
+    }
+
+    #[test]
+    fn xkb_modifier_swaps_are_not_converted() {
+        for option in [
+            "ctrl:swapcaps",
+            "ctrl:swap_lalt_lctl",
+            "ctrl:swap_lwin_lctl",
+            "altwin:swap_alt_win",
+            "altwin:swap_lalt_lwin",
+        ] {
+            let imported = import_xkb_options(option);
+            assert!(imported.remap.is_empty());
+            assert_eq!(
+                imported.unsupported,
+                vec![format!(
+                    "xkb option {} (swapping a modifier key can't be expressed with remap rules)",
+                    option
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn interception_dual_function_keys() {
+        let imported = import_interception_dual_function_keys(
+            "TIMING:
+  TAP_MILLISEC: 200
+MAPPINGS:
+  - KEY: KEY_CAPSLOCK
+    TAP: KEY_ESC
+    HOLD: KEY_LEFTCTRL
+  - KEY: KEY_ENTER
+    TAP: KEY_ENTER
+    HOLD: [KEY_RIGHTCTRL, KEY_RIGHTSHIFT]
+  - KEY: KEY_SPACE
+    TAP: KEY_SPACE
+    HOLD: KEY_NOSUCHKEY
+",
+        )
+        .unwrap();
+        assert_eq!(
+            imported.dual_role,
+            vec![
+                (
+                    "KEY_CAPSLOCK".to_string(),
+                    keys(&["KEY_LEFTCTRL"]),
+                    keys(&["KEY_ESC"])
+                ),
+                (
+                    "KEY_ENTER".to_string(),
+                    keys(&["KEY_RIGHTCTRL", "KEY_RIGHTSHIFT"]),
+                    keys(&["KEY_ENTER"])
+                ),
+            ]
+        );
+        assert_eq!(
+            imported.unsupported,
+            vec![
+                "TIMING TAP_MILLISEC (dual role keys are tapped when no other key \
+                 is pressed while they are held)",
+                "mapping for KEY_SPACE (unknown key `KEY_NOSUCHKEY`)"
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_output() {
+        let mut imported = Imported::default();
+        imported.map("KEY_CAPSLOCK", "KEY_ESC");
+        imported
+            .unsupported
+            .push("xkb option compose:ralt".to_string());
+        assert_eq!(
+            imported.to_toml(Path::new("keyboard")),
+            "# Imported from keyboard
+# Not converted: xkb option compose:ralt
+
+# Run `sudo evremap list-devices` and set the name of your keyboard here
+device_name = \"\"
+
+[[remap]]
+input = [\"KEY_CAPSLOCK\"]
+output = [\"KEY_ESC\"]
+"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! The remapping engine behind the `evremap` command, for embedding
+//! in other programs.
+//!
+//! A [`MappingConfig`] can be loaded from a config file with
+//! [`MappingConfig::from_file`], or built up in code:
+//!
+//! ```no_run
+//! use evremap::{DeviceInfo, DeviceMatch, InputMapper, KeyCode, Mapping, MappingConfig, Modifier};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = MappingConfig::new(DeviceMatch::by_name("AT Translated Set 2 keyboard"))
+//!     .with_mapping(Mapping::dual_role(
+//!         KeyCode::KEY_CAPSLOCK,
+//!         [KeyCode::KEY_LEFTCTRL],
+//!         [KeyCode::KEY_ESC],
+//!     ))
+//!     .with_mapping(Mapping::remap([KeyCode::KEY_LEFT], [KeyCode::KEY_HOME]).modifiers([Modifier::Alt]));
+//!
+//! let device = DeviceInfo::with_match(config.device())?;
+//! let mut mapper = InputMapper::create_mapper(&device.path, config)?;
+//! mapper.run_mapper()
+//! # }
+//! ```
+
+mod conditions;
+pub mod deviceinfo;
+mod helper;
+pub mod mapping;
+mod output;
+mod remapper;
+mod systemd;
+
+pub use deviceinfo::{DeviceInfo, DeviceMatch};
+pub use mapping::{
+    Condition, ConfigError, KeyCode, LedCode, LedIndicator, Mapping, MappingConfig, Modifier,
+    ModifierOptions, OutputDevice, RemapBuilder, SwitchCode, TapTiming,
+};
+pub use remapper::InputMapper;
+
+/// Entry points that are used by the `evremap` binary.  They are not
+/// part of the stable API.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::helper::{list_devices as helper_list_devices, run_helper, AllowRule};
+    pub use crate::systemd::{connect as connect_notify, notify};
+}
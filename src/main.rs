@@ -1,13 +1,16 @@
-use crate::mapping::*;
-use crate::remapper::*;
-use anyhow::{Context, Result};
-use std::path::PathBuf;
+use anyhow::{anyhow, bail, Context, Result};
+use evremap::cli;
+use evremap::deviceinfo;
+use evremap::mapping::*;
+use evremap::InputMapper;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use structopt::StructOpt;
 
-mod deviceinfo;
-mod mapping;
-mod remapper;
+mod check;
+mod explain;
+mod import;
+mod privileges;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -19,11 +22,83 @@ enum Opt {
     /// Rather than running the remapper, list currently available devices.
     /// This is helpful to check their names when setting up the initial
     /// configuration
-    ListDevices,
+    ListDevices {
+        /// Output format; either `text` or `json`
+        #[structopt(long, default_value = "text")]
+        format: deviceinfo::OutputFormat,
+
+        /// Also show the supported event types, keys, LEDs and switches
+        #[structopt(short, long)]
+        verbose: bool,
+
+        /// Also show whether each device is grabbed by another process.
+        /// This briefly grabs every device that isn't, so input from
+        /// them may be lost while the devices are listed.
+        #[structopt(long)]
+        check_grab: bool,
+
+        /// Only list devices that look like keyboards
+        #[structopt(long)]
+        keyboards: bool,
+    },
 
     /// Show a list of possible KEY_XXX values
     ListKeys,
 
+    /// Load a remapper config and report problems with it, such as
+    /// rules that can never match.  Exits with a non-zero status if
+    /// errors are found.
+    Check {
+        /// Specify the configuration file to be checked
+        #[structopt(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+    },
+
+    /// Load a remapper config and print the effective mappings, after
+    /// includes and aliases are applied, in the order that the remapper
+    /// considers them
+    Explain {
+        /// Specify the configuration file to be explained
+        #[structopt(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// Also write an HTML page with a keyboard diagram for each
+        /// combination of modifiers used by the config
+        #[structopt(long)]
+        html: Option<PathBuf>,
+    },
+
+    /// Convert the configuration of another remapping tool into an
+    /// evremap config, which is printed to stdout.  Anything that
+    /// cannot be converted is reported as a warning.
+    Import {
+        /// The format of the file; one of `keyd`, `xkb-options` or
+        /// `interception-dual-function-keys`
+        #[structopt(long)]
+        from: import::ImportFormat,
+
+        /// The file to convert
+        #[structopt(name = "FILE")]
+        file: PathBuf,
+    },
+
+    /// Print the key events produced by a device, without grabbing it.
+    /// This is helpful to discover the KEY_XXX names of the keys on
+    /// your keyboard.
+    Watch {
+        /// The device to watch; either its path, such as /dev/input/event3,
+        /// or its name as shown by `list-devices`.
+        /// May be omitted if a configuration file is specified, in which
+        /// case the device from the config is used.
+        #[structopt(name = "DEVICE")]
+        device: Option<String>,
+
+        /// Load this configuration and show what the remapper would
+        /// produce for each event
+        #[structopt(short, long)]
+        config: Option<PathBuf>,
+    },
+
     /// Load a remapper config and run the remapper.
     /// This usually requires running as root to obtain exclusive access
     /// to the input devices.
@@ -35,24 +110,125 @@ enum Opt {
         /// Number of seconds for user to release keys on startup
         #[structopt(short, long, default_value = "2")]
         delay: f64,
+
+        /// Rather than opening the devices ourselves, ask the
+        /// `evremap helper` listening on this socket to open them.
+        /// This allows the remapper to run without root.
+        #[structopt(long, value_name = "SOCKET")]
+        helper: Option<PathBuf>,
+
+        /// Remap this device, such as /dev/input/event3, if it matches
+        /// the config; otherwise exit without doing anything.  This is
+        /// used by the evremap@.service template unit.
+        #[structopt(long, value_name = "PATH")]
+        device: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        privileges: privileges::PrivilegeOpts,
+    },
+
+    /// Run the privileged helper, which opens and grabs input devices
+    /// and creates the output devices on behalf of `evremap remap
+    /// --helper`, passing their file descriptors over a unix socket.
+    /// This usually requires running as root.
+    Helper {
+        /// The path of the socket to listen on
+        #[structopt(long, default_value = "/run/evremap.sock")]
+        socket: PathBuf,
+
+        /// Allow the members of this group to connect to the socket.
+        /// Otherwise only root may connect.
+        #[structopt(long)]
+        group: Option<String>,
+
+        /// Allow a user to open the devices that match, as USER:DEVICE,
+        /// where DEVICE is a device path or a name that may contain `*`
+        /// and `?` wildcards.  May be repeated.  Requests from users
+        /// that aren't allowed any devices are rejected.
+        #[structopt(long, value_name = "USER:DEVICE", number_of_values = 1)]
+        allow: Vec<cli::AllowRule>,
     },
 }
 
 pub fn list_keys() -> Result<()> {
-    let mut keys: Vec<String> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
-        .iter()
-        .filter_map(|code| match code {
-            EventCode::EV_KEY(_) => Some(format!("{}", code)),
-            _ => None,
-        })
-        .collect();
-    keys.sort();
-    for key in keys {
+    for key in all_key_names() {
         println!("{}", key);
     }
     Ok(())
 }
 
+/// Runs a mapper for each of the devices on its own thread.
+/// Devices that can't be opened or grabbed, perhaps because another
+/// program has grabbed them, are skipped.  Privileges are dropped once
+/// the others have been opened, and before the threads are started,
+/// as each thread keeps the capabilities that it was started with.
+/// Returns when any of them fail.
+fn run_mappers(
+    devices: Vec<deviceinfo::DeviceInfo>,
+    config: MappingConfig,
+    helper: Option<&Path>,
+    privileges: &privileges::PrivilegeOpts,
+) -> Result<()> {
+    let mut mappers = vec![];
+    for device in &devices {
+        log::info!("Remapping {} ({})", device.name, device.path.display());
+        match create_mapper(&device.path, config.clone(), helper) {
+            Ok(mapper) => mappers.push((device.path.clone(), mapper)),
+            Err(err) => log::error!(
+                "Skipping device: {:#}",
+                err.context(format!("remapping {}", device.path.display()))
+            ),
+        }
+    }
+    if mappers.is_empty() {
+        bail!(
+            "none of the {} matching devices could be remapped",
+            devices.len()
+        );
+    }
+    // The seccomp filter doesn't allow connecting, so connect now
+    cli::connect_notify();
+    privileges.apply()?;
+    cli::notify("READY=1");
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    for (path, mut mapper) in mappers {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let context = || format!("remapping {}", path.display());
+            tx.send(mapper.run_mapper().with_context(context)).ok();
+        });
+    }
+    drop(tx);
+
+    match rx.recv() {
+        Ok(result) => result,
+        Err(_) => Ok(()),
+    }
+}
+
+/// Runs a mapper for a single device on the current thread
+fn run_mapper(
+    path: &Path,
+    config: MappingConfig,
+    helper: Option<&Path>,
+    privileges: &privileges::PrivilegeOpts,
+) -> Result<()> {
+    let mut mapper = create_mapper(path, config, helper)?;
+    // The seccomp filter doesn't allow connecting, so connect now
+    cli::connect_notify();
+    privileges.apply()?;
+    cli::notify("READY=1");
+    mapper.run_mapper()
+}
+
+fn create_mapper(path: &Path, config: MappingConfig, helper: Option<&Path>) -> Result<InputMapper> {
+    match helper {
+        Some(socket) => InputMapper::create_mapper_via_helper(socket, path, config),
+        None => InputMapper::create_mapper(path, config),
+    }
+}
+
 fn setup_logger() {
     let mut builder = pretty_env_logger::formatted_timed_builder();
     if let Ok(s) = std::env::var("EVREMAP_LOG") {
@@ -68,9 +244,49 @@ fn main() -> Result<()> {
     let opt = Opt::from_args();
 
     match opt {
-        Opt::ListDevices => deviceinfo::list_devices(),
+        Opt::ListDevices {
+            format,
+            verbose,
+            check_grab,
+            keyboards,
+        } => deviceinfo::list_devices(format, verbose, check_grab, keyboards),
         Opt::ListKeys => list_keys(),
-        Opt::Remap { config_file, delay } => {
+        Opt::Check { config_file } => check::check_config(&config_file),
+        Opt::Explain { config_file, html } => {
+            explain::explain_config(&config_file, html.as_deref())
+        }
+        Opt::Import { from, file } => import::import_config(from, &file),
+        Opt::Watch { device, config } => {
+            let mapping_config = match &config {
+                Some(config_file) => Some(MappingConfig::from_file(config_file).context(
+                    format!("loading MappingConfig from {}", config_file.display()),
+                )?),
+                None => None,
+            };
+
+            let path = match (device, &mapping_config) {
+                (Some(device), _) if Path::new(&device).exists() => PathBuf::from(device),
+                (Some(device), _) => {
+                    let criteria = deviceinfo::DeviceMatch::by_name(device);
+                    deviceinfo::DeviceInfo::with_match(&criteria)?.path
+                }
+                (None, Some(mapping_config)) => {
+                    deviceinfo::DeviceInfo::with_match(mapping_config.device())?.path
+                }
+                (None, None) => bail!("specify either a DEVICE or a --config file"),
+            };
+
+            log::info!("Watching {}; press CTRL-C to stop", path.display());
+            let mut mapper = InputMapper::create_watcher(path, mapping_config)?;
+            mapper.run_mapper()
+        }
+        Opt::Remap {
+            config_file,
+            delay,
+            helper,
+            device,
+            privileges,
+        } => {
             let mapping_config = MappingConfig::from_file(&config_file).context(format!(
                 "loading MappingConfig from {}",
                 config_file.display()
@@ -79,13 +295,68 @@ fn main() -> Result<()> {
             log::warn!("Short delay: release any keys now!");
             std::thread::sleep(Duration::from_secs_f64(delay));
 
-            let device_info = deviceinfo::DeviceInfo::with_name(
-                &mapping_config.device_name,
-                mapping_config.phys.as_deref(),
-            )?;
+            // Without access to /dev/input, ask the helper what is there
+            let devices = match &helper {
+                Some(socket) => Some(cli::helper_list_devices(socket)?),
+                None => None,
+            };
 
-            let mut mapper = InputMapper::create_mapper(device_info.path, mapping_config.mappings)?;
-            mapper.run_mapper()
+            if let Some(path) = device {
+                let device_info = match devices {
+                    Some(devices) => devices
+                        .into_iter()
+                        .find(|info| info.path == path)
+                        .ok_or_else(|| anyhow!("{} is not an input device", path.display()))?,
+                    None => deviceinfo::DeviceInfo::with_path(path)?,
+                };
+                if !mapping_config.device().matches(&device_info) {
+                    log::info!(
+                        "{} ({}) doesn't match {}; there is nothing to do",
+                        device_info.name,
+                        device_info.path.display(),
+                        mapping_config.device()
+                    );
+                    // A Type=notify unit that exits before it is ready
+                    // is treated as having failed
+                    cli::notify("READY=1");
+                    return Ok(());
+                }
+                return run_mapper(
+                    &device_info.path,
+                    mapping_config,
+                    helper.as_deref(),
+                    &privileges,
+                );
+            }
+
+            if mapping_config.device().all_keyboards {
+                let devices = match devices {
+                    Some(devices) => {
+                        deviceinfo::DeviceInfo::select_all(devices, mapping_config.device())?
+                    }
+                    None => deviceinfo::DeviceInfo::all_matching(mapping_config.device())?,
+                };
+                return run_mappers(devices, mapping_config, helper.as_deref(), &privileges);
+            }
+
+            let device_info = match devices {
+                Some(devices) => {
+                    deviceinfo::DeviceInfo::select_first(devices, mapping_config.device())?
+                }
+                None => deviceinfo::DeviceInfo::with_match(mapping_config.device())?,
+            };
+
+            run_mapper(
+                &device_info.path,
+                mapping_config,
+                helper.as_deref(),
+                &privileges,
+            )
         }
+        Opt::Helper {
+            socket,
+            group,
+            allow,
+        } => cli::run_helper(&socket, group.as_deref(), allow),
     }
 }
@@ -1,126 +1,1966 @@
+use crate::deviceinfo::{DeviceMatch, NameMatch};
 use anyhow::Context;
-pub use evdev_rs::enums::{EventCode, EventType, EV_KEY as KeyCode};
+pub use evdev_rs::enums::{
+    EventCode, EventType, EV_KEY as KeyCode, EV_LED as LedCode, EV_SW as SwitchCode,
+};
+use regex::Regex;
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
+/// The device selection and mappings for a remapper.  It is built with
+/// `MappingConfig::new` and the `with_` methods, or loaded from a file.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct MappingConfig {
-    pub device_name: String,
+    pub(crate) device: DeviceMatch,
+    pub(crate) mappings: Vec<Mapping>,
+    pub(crate) modifier_keys: HashSet<KeyCode>,
+    pub(crate) leds: Vec<LedIndicator>,
+    pub(crate) tap_timing: TapTiming,
+    pub(crate) output: OutputDevice,
+}
+
+/// Properties of the virtual output device.  Anything that isn't set
+/// is copied from the input device, except for the name, which
+/// defaults to one that identifies the input device by its path.
+/// The phys is always prefixed with `evremap/`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct OutputDevice {
+    pub name: Option<String>,
     pub phys: Option<String>,
-    pub mappings: Vec<Mapping>,
+    pub bustype: Option<u16>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    /// Event codes that the output device reports in addition
+    /// to those of the input device
+    pub capabilities: Vec<EventCode>,
+    /// Emit the pointer events, such as relative motion and mouse
+    /// buttons, from a second virtual device
+    pub split_pointer: bool,
+    /// The name of the pointer device.  Defaults to the name of
+    /// the output device with ` pointer` appended.
+    pub pointer_name: Option<String>,
+}
+
+/// Controls the timing of the key events that are emitted when a
+/// dual role key is tapped.  By default they are all emitted at
+/// once, which some applications treat as a zero length press.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TapTiming {
+    /// The shortest time that the tapped keys are held for
+    pub min_press: Duration,
+    /// The time between each of the key events of the tap
+    pub spacing: Duration,
+}
+
+/// An LED that is lit while all of its keys are held on the
+/// input device
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct LedIndicator {
+    pub led: LedCode,
+    pub keys: HashSet<KeyCode>,
+}
+
+impl OutputDevice {
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name.replace(name.into());
+        self
+    }
+
+    pub fn with_phys(mut self, phys: impl Into<String>) -> Self {
+        self.phys.replace(phys.into());
+        self
+    }
+
+    /// Sets the bus type, vendor and product ids
+    pub fn with_ids(mut self, bustype: u16, vendor: u16, product: u16) -> Self {
+        self.bustype.replace(bustype);
+        self.vendor.replace(vendor);
+        self.product.replace(product);
+        self
+    }
+
+    /// Adds event codes that the output device reports in addition
+    /// to those of the input device
+    pub fn with_capabilities(mut self, codes: impl IntoIterator<Item = EventCode>) -> Self {
+        self.capabilities.extend(codes);
+        self
+    }
+
+    /// Emits the pointer events from a second device, which is named
+    /// `pointer_name` if it is given
+    pub fn with_split_pointer(mut self, pointer_name: Option<String>) -> Self {
+        self.split_pointer = true;
+        self.pointer_name = pointer_name;
+        self
+    }
+}
+
+impl TapTiming {
+    pub fn new(min_press: Duration, spacing: Duration) -> Self {
+        Self { min_press, spacing }
+    }
+}
+
+/// The keys that are treated as modifiers unless the config
+/// specifies otherwise
+pub fn default_modifier_keys() -> HashSet<KeyCode> {
+    [
+        KeyCode::KEY_FN,
+        KeyCode::KEY_LEFTALT,
+        KeyCode::KEY_RIGHTALT,
+        KeyCode::KEY_LEFTMETA,
+        KeyCode::KEY_RIGHTMETA,
+        KeyCode::KEY_LEFTCTRL,
+        KeyCode::KEY_RIGHTCTRL,
+        KeyCode::KEY_LEFTSHIFT,
+        KeyCode::KEY_RIGHTSHIFT,
+    ]
+    .iter()
+    .cloned()
+    .collect()
 }
 
 impl MappingConfig {
+    /// Creates a config for the devices selected by `device`, with no
+    /// mappings and the default set of modifier keys
+    pub fn new(device: DeviceMatch) -> Self {
+        Self {
+            device,
+            mappings: vec![],
+            modifier_keys: default_modifier_keys(),
+            leds: vec![],
+            tap_timing: TapTiming::default(),
+            output: OutputDevice::default(),
+        }
+    }
+
+    /// Lights `led` while all of `keys` are held
+    pub fn with_led(mut self, led: LedCode, keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        self.leds.push(LedIndicator {
+            led,
+            keys: keys.into_iter().collect(),
+        });
+        self
+    }
+
+    /// Sets the timing of the key events emitted for dual role taps
+    pub fn with_tap_timing(mut self, tap_timing: TapTiming) -> Self {
+        self.tap_timing = tap_timing;
+        self
+    }
+
+    /// Sets the properties of the virtual output device
+    pub fn with_output(mut self, output: OutputDevice) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Appends a mapping.  Remap rules are applied in the order
+    /// that they are added.
+    pub fn with_mapping(mut self, mapping: impl Into<Mapping>) -> Self {
+        let mapping = mapping.into();
+        if let Mapping::DualRole { hold, .. } = &mapping {
+            self.modifier_keys.extend(hold.iter().cloned());
+        }
+        self.mappings.push(mapping);
+        self
+    }
+
+    /// The criteria that select the input devices
+    pub fn device(&self) -> &DeviceMatch {
+        &self.device
+    }
+
+    /// The mappings, in the order that they are applied
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// The keys that are treated as modifiers.  Modifiers are pressed
+    /// before and released after other keys, and remain visible to
+    /// later remap rules after being used as an input or output.
+    pub fn modifier_keys(&self) -> &HashSet<KeyCode> {
+        &self.modifier_keys
+    }
+
+    /// LEDs on the input device that evremap controls
+    pub fn leds(&self) -> &[LedIndicator] {
+        &self.leds
+    }
+
+    /// How the keys of a dual role tap are spaced out in time
+    pub fn tap_timing(&self) -> TapTiming {
+        self.tap_timing
+    }
+
+    /// Overrides for the properties of the virtual output device
+    pub fn output(&self) -> &OutputDevice {
+        &self.output
+    }
+
+    /// Replaces the set of keys that are treated as modifiers.
+    /// The keys produced by holding a dual role key are always
+    /// modifiers, whether or not they are included here.
+    pub fn with_modifier_keys(mut self, keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        self.modifier_keys = keys.into_iter().collect();
+        for map in &self.mappings {
+            if let Mapping::DualRole { hold, .. } = map {
+                self.modifier_keys.extend(hold.iter().cloned());
+            }
+        }
+        self
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let path = path.as_ref();
-        let toml_data = std::fs::read_to_string(path)
-            .context(format!("reading toml from {}", path.display()))?;
-        let config_file: ConfigFile =
-            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
-        let mut mappings = vec![];
-        for dual in config_file.dual_role {
-            mappings.push(dual.into());
-        }
-        for remap in config_file.remap {
-            mappings.push(remap.into());
+        let loaded = LoadedFile::load(path.as_ref(), &mut vec![])?;
+
+        if loaded.device.is_empty() {
+            anyhow::bail!(
+                "no input device specified; set match, device_name, device_name_regex, \
+                 device_path, phys, uniq, bustype, vendor, product or version"
+            );
+        }
+
+        let mappings: Vec<Mapping> = loaded
+            .dual_role
+            .into_iter()
+            .chain(loaded.disable)
+            .chain(loaded.remap)
+            .map(|named| named.mapping)
+            .collect();
+
+        // Keys produced by holding a dual role key are always modifiers
+        let mut modifier_keys = loaded.modifier_keys.unwrap_or_else(default_modifier_keys);
+        for map in &mappings {
+            if let Mapping::DualRole { hold, .. } = map {
+                modifier_keys.extend(hold.iter().cloned());
+            }
         }
+
         Ok(Self {
-            device_name: config_file.device_name,
-            phys: config_file.phys,
+            device: loaded.device,
             mappings,
+            modifier_keys,
+            leds: loaded.leds,
+            tap_timing: loaded.tap_timing.unwrap_or_default(),
+            output: loaded.output.unwrap_or_default(),
         })
     }
 }
 
+/// The formats that a config file may be written in,
+/// which is determined by its file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match ext.as_deref() {
+            Some("json") => Self::Json,
+            Some("yaml") | Some("yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::Toml => "toml",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+/// A mapping along with the optional name that allows it to be
+/// overridden or disabled by a file that includes it
+struct NamedMapping {
+    name: Option<String>,
+    mapping: Mapping,
+}
+
+/// Adds `rules` to `into`.  A rule with the same name as one
+/// that is already present replaces it in place, otherwise it
+/// is appended.
+fn merge_rules(into: &mut Vec<NamedMapping>, rules: Vec<NamedMapping>) {
+    for rule in rules {
+        let existing = rule
+            .name
+            .as_ref()
+            .and_then(|name| into.iter().position(|r| r.name.as_ref() == Some(name)));
+        match existing {
+            Some(idx) => into[idx] = rule,
+            None => into.push(rule),
+        }
+    }
+}
+
+/// The result of loading a config file along with the files
+/// that it includes
+struct LoadedFile {
+    device: DeviceMatch,
+    aliases: HashMap<String, Vec<KeyCode>>,
+    modifier_keys: Option<HashSet<KeyCode>>,
+    leds: Vec<LedIndicator>,
+    tap_timing: Option<TapTiming>,
+    output: Option<OutputDevice>,
+    dual_role: Vec<NamedMapping>,
+    disable: Vec<NamedMapping>,
+    remap: Vec<NamedMapping>,
+}
+
+impl LoadedFile {
+    /// Loads `path`.  `stack` holds the files that are in the
+    /// process of being loaded and is used to detect include cycles.
+    fn load(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Self> {
+        let canonical =
+            std::fs::canonicalize(path).context(format!("resolving {}", path.display()))?;
+        if stack.contains(&canonical) {
+            anyhow::bail!("{} includes itself", path.display());
+        }
+        stack.push(canonical);
+
+        let format = ConfigFormat::from_path(path);
+        let data = std::fs::read_to_string(path).context(format!(
+            "reading {} from {}",
+            format,
+            path.display()
+        ))?;
+        // Key names in TOML files carry their position, so that errors
+        // can say where they are; the other formats don't provide it
+        let context = || format!("parsing {} from {}", format, path.display());
+        let loaded = match format {
+            ConfigFormat::Toml => {
+                let config_file: ConfigFile<toml::Spanned<String>> =
+                    toml::from_str(&data).with_context(context)?;
+                Self::resolve(path, &data, config_file, stack)?
+            }
+            ConfigFormat::Json => {
+                let config_file: ConfigFile<String> =
+                    serde_json::from_str(&data).with_context(context)?;
+                Self::resolve(path, &data, config_file, stack)?
+            }
+            ConfigFormat::Yaml => {
+                let config_file: ConfigFile<String> =
+                    serde_yaml::from_str(&data).with_context(context)?;
+                Self::resolve(path, &data, config_file, stack)?
+            }
+        };
+
+        stack.pop();
+        Ok(loaded)
+    }
+
+    /// Merges the files included by `config_file` and resolves
+    /// its key names
+    fn resolve<K: KeyName>(
+        path: &Path,
+        data: &str,
+        config_file: ConfigFile<K>,
+        stack: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let mut loaded = Self {
+            device: DeviceMatch::default(),
+            aliases: HashMap::new(),
+            modifier_keys: None,
+            leds: vec![],
+            tap_timing: None,
+            output: None,
+            dual_role: vec![],
+            disable: vec![],
+            remap: vec![],
+        };
+
+        // Included files are merged first, in the order listed, with
+        // their paths resolved relative to the including file
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for include in &config_file.include {
+            let include_path = base.join(include);
+            let included = Self::load(&include_path, stack).context(format!(
+                "loading {} included from {}",
+                include_path.display(),
+                path.display()
+            ))?;
+            if loaded.device.is_empty() {
+                loaded.device = included.device;
+            }
+            loaded.aliases.extend(included.aliases);
+            if included.modifier_keys.is_some() {
+                loaded.modifier_keys = included.modifier_keys;
+            }
+            loaded.leds.extend(included.leds);
+            if included.tap_timing.is_some() {
+                loaded.tap_timing = included.tap_timing;
+            }
+            if included.output.is_some() {
+                loaded.output = included.output;
+            }
+            merge_rules(&mut loaded.dual_role, included.dual_role);
+            merge_rules(&mut loaded.disable, included.disable);
+            merge_rules(&mut loaded.remap, included.remap);
+        }
+
+        let device = config_file.device_match()?;
+        if !device.is_empty() {
+            loaded.device = device;
+        }
+
+        // Aliases are defined in terms of keys, rather than other aliases
+        let no_aliases = HashMap::new();
+        let resolver = KeyResolver {
+            path,
+            source: data,
+            aliases: &no_aliases,
+        };
+        for (name, alias) in &config_file.aliases {
+            // An alias that could also be read as a key or a modifier
+            // would silently change the meaning of that name
+            let meaning = match (parse_key(name), Modifier::from_name(name)) {
+                (Some(code), _) => Some(format!("the key {:?}", code)),
+                (None, Some(modifier)) => Some(format!("the modifier {}", modifier)),
+                (None, None) => None,
+            };
+            if let Some(meaning) = meaning {
+                return Err(ConfigError::InvalidAlias {
+                    alias: name.clone(),
+                    file: path.display().to_string(),
+                    meaning,
+                }
+                .into());
+            }
+            let location = KeyLocation {
+                table: "aliases",
+                index: None,
+                field: name,
+                element: None,
+            };
+            let keys = match alias {
+                AliasConfig::Key(key) => resolver.resolve(key, &location)?,
+                AliasConfig::Group(keys) => resolver.resolve_list(keys, "aliases", None, name)?,
+            };
+            loaded.aliases.insert(name.clone(), keys);
+        }
+
+        let resolver = KeyResolver {
+            path,
+            source: data,
+            aliases: &loaded.aliases,
+        };
+        if let Some(keys) = &config_file.modifier_keys {
+            let keys = resolver.resolve_list(keys, "", None, "modifier_keys")?;
+            loaded.modifier_keys.replace(keys.into_iter().collect());
+        }
+
+        if let Some(tap_timing) = &config_file.tap_timing {
+            loaded.tap_timing.replace(TapTiming::new(
+                Duration::from_millis(tap_timing.min_press_ms),
+                Duration::from_millis(tap_timing.spacing_ms),
+            ));
+        }
+
+        if let Some(output) = &config_file.output {
+            loaded.output.replace(output.resolve(path)?);
+        }
+
+        for (idx, led) in config_file.led.into_iter().enumerate() {
+            loaded.leds.push(led.resolve(idx, path, &resolver)?);
+        }
+
+        let mut dual_role = vec![];
+        for (idx, dual) in config_file.dual_role.into_iter().enumerate() {
+            dual_role.push(dual.resolve(idx, &resolver)?);
+        }
+        let mut disable = vec![];
+        for (idx, rule) in config_file.disable.into_iter().enumerate() {
+            disable.push(rule.resolve(idx, &resolver)?);
+        }
+        let mut remap = vec![];
+        for (idx, rule) in config_file.remap.into_iter().enumerate() {
+            remap.push(rule.resolve(idx, &resolver)?);
+        }
+        merge_rules(&mut loaded.dual_role, dual_role);
+        merge_rules(&mut loaded.disable, disable);
+        merge_rules(&mut loaded.remap, remap);
+
+        for name in &config_file.disable_rules {
+            let count =
+                |loaded: &Self| loaded.dual_role.len() + loaded.disable.len() + loaded.remap.len();
+            let before = count(&loaded);
+            loaded.dual_role.retain(|r| r.name.as_ref() != Some(name));
+            loaded.disable.retain(|r| r.name.as_ref() != Some(name));
+            loaded.remap.retain(|r| r.name.as_ref() != Some(name));
+            if before == count(&loaded) {
+                anyhow::bail!(
+                    "{}: disable_rules refers to `{}` but there is no rule with that name",
+                    path.display(),
+                    name
+                );
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+/// A rule that changes the keys seen by the output device.  Mappings
+/// are created with `Mapping::dual_role`, `Mapping::remap` and
+/// `Mapping::disable`.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum Mapping {
+    #[non_exhaustive]
     DualRole {
         input: KeyCode,
         hold: Vec<KeyCode>,
         tap: Vec<KeyCode>,
+        /// The mapping only applies while all of these are met
+        conditions: Vec<Condition>,
     },
+    #[non_exhaustive]
     Remap {
         input: HashSet<KeyCode>,
+        /// Side agnostic modifiers that must also be held; either
+        /// the left or right key satisfies each of these
+        modifiers: HashSet<Modifier>,
         output: HashSet<KeyCode>,
+        modifier_options: ModifierOptions,
+        /// The mapping only applies while all of these are met
+        conditions: Vec<Condition>,
+    },
+    /// Swallows a key, or chord, so that it never reaches the output
+    /// device.  When the chord includes keys that aren't modifiers,
+    /// only those keys are swallowed and held modifiers are left alone.
+    #[non_exhaustive]
+    Disable {
+        input: HashSet<KeyCode>,
+        /// Side agnostic modifiers that must also be held
+        modifiers: HashSet<Modifier>,
+        /// Remove the key from the capabilities of the output device
+        remove_capability: bool,
+        /// The mapping only applies while all of these are met
+        conditions: Vec<Condition>,
     },
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(try_from = "String")]
-struct KeyCodeWrapper {
-    pub code: KeyCode,
+/// Something about the system that a mapping can depend on
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Condition {
+    /// A switch, such as SW_TABLET_MODE or SW_LID, is on or off.
+    /// The switch may belong to any input device.
+    #[non_exhaustive]
+    Switch { switch: SwitchCode, on: bool },
+    /// A device whose name matches `name`, which may contain `*`
+    /// and `?` wildcards, is or isn't connected
+    #[non_exhaustive]
+    Device { name: String, present: bool },
+}
+
+impl Condition {
+    pub fn switch(switch: SwitchCode, on: bool) -> Self {
+        Self::Switch { switch, on }
+    }
+
+    pub fn device(name: impl Into<String>, present: bool) -> Self {
+        Self::Device {
+            name: name.into(),
+            present,
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Switch { switch, on } => {
+                write!(fmt, "{:?} is {}", switch, if *on { "on" } else { "off" })
+            }
+            Self::Device { name, present } => write!(
+                fmt,
+                "a device named `{}` is {}",
+                name,
+                if *present {
+                    "connected"
+                } else {
+                    "not connected"
+                }
+            ),
+        }
+    }
+}
+
+impl Mapping {
+    /// Creates a dual role mapping, which produces `hold` while `input`
+    /// is held, or `tap` if it is released without another key being
+    /// pressed
+    pub fn dual_role(
+        input: KeyCode,
+        hold: impl IntoIterator<Item = KeyCode>,
+        tap: impl IntoIterator<Item = KeyCode>,
+    ) -> Self {
+        Self::DualRole {
+            input,
+            hold: hold.into_iter().collect(),
+            tap: tap.into_iter().collect(),
+            conditions: vec![],
+        }
+    }
+
+    /// Adds a condition that must be met for the mapping to apply
+    pub fn when(mut self, condition: Condition) -> Self {
+        match &mut self {
+            Self::DualRole { conditions, .. }
+            | Self::Remap { conditions, .. }
+            | Self::Disable { conditions, .. } => conditions.push(condition),
+        }
+        self
+    }
+
+    /// The conditions that must be met for the mapping to apply
+    pub fn conditions(&self) -> &[Condition] {
+        match self {
+            Self::DualRole { conditions, .. }
+            | Self::Remap { conditions, .. }
+            | Self::Disable { conditions, .. } => conditions,
+        }
+    }
+
+    /// Creates a mapping that swallows `input`.  If `remove_capability`
+    /// is set then the key is also removed from the capabilities of the
+    /// output device, which requires `input` to be a single key.
+    pub fn disable(input: impl IntoIterator<Item = KeyCode>, remove_capability: bool) -> Self {
+        Self::Disable {
+            input: input.into_iter().collect(),
+            modifiers: HashSet::new(),
+            remove_capability,
+            conditions: vec![],
+        }
+    }
+
+    /// Starts building a remap rule that produces `output` while all
+    /// of the `input` keys are held
+    pub fn remap(
+        input: impl IntoIterator<Item = KeyCode>,
+        output: impl IntoIterator<Item = KeyCode>,
+    ) -> RemapBuilder {
+        RemapBuilder {
+            input: input.into_iter().collect(),
+            modifiers: HashSet::new(),
+            output: output.into_iter().collect(),
+            modifier_options: ModifierOptions::default(),
+            conditions: vec![],
+        }
+    }
+}
+
+/// Builds a `Mapping::Remap`; see `Mapping::remap`
+#[derive(Debug, Clone)]
+pub struct RemapBuilder {
+    input: HashSet<KeyCode>,
+    modifiers: HashSet<Modifier>,
+    output: HashSet<KeyCode>,
+    modifier_options: ModifierOptions,
+    conditions: Vec<Condition>,
 }
 
-impl Into<KeyCode> for KeyCodeWrapper {
-    fn into(self) -> KeyCode {
-        self.code
+impl RemapBuilder {
+    /// Side agnostic modifiers that must also be held
+    pub fn modifiers(mut self, modifiers: impl IntoIterator<Item = Modifier>) -> Self {
+        self.modifiers.extend(modifiers);
+        self
+    }
+
+    /// Modifiers from the input that remain held rather than
+    /// being consumed
+    pub fn preserve_modifiers(mut self, keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        self.modifier_options.preserve.extend(keys);
+        self
+    }
+
+    /// Held modifiers that are released while the rule applies
+    pub fn suppress_modifiers(mut self, keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        self.modifier_options.suppress.extend(keys);
+        self
+    }
+
+    /// Modifiers that are held in addition to the output
+    pub fn add_modifiers(mut self, keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        self.modifier_options.add.extend(keys);
+        self
+    }
+
+    /// Adds a condition that must be met for the rule to apply
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn build(self) -> Mapping {
+        Mapping::Remap {
+            input: self.input,
+            modifiers: self.modifiers,
+            output: self.output,
+            modifier_options: self.modifier_options,
+            conditions: self.conditions,
+        }
+    }
+}
+
+impl From<RemapBuilder> for Mapping {
+    fn from(builder: RemapBuilder) -> Self {
+        builder.build()
+    }
+}
+
+/// Controls what happens to held modifiers when a Remap applies.
+/// By default, modifiers that are part of the input are consumed
+/// and other held modifiers pass through to the output.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ModifierOptions {
+    /// Modifiers from the input that remain held rather than
+    /// being consumed
+    pub preserve: HashSet<KeyCode>,
+    /// Held modifiers that are released while the rule applies
+    pub suppress: HashSet<KeyCode>,
+    /// Modifiers that are held in addition to the output
+    pub add: HashSet<KeyCode>,
+}
+
+/// A virtual modifier that matches both the left and right
+/// versions of a modifier key
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Modifier {
+    Ctrl,
+    Alt,
+    Shift,
+    Meta,
+}
+
+impl Modifier {
+    /// Parses the virtual modifier names `CTRL`, `ALT`, `SHIFT`
+    /// and `META`, case insensitively
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "CTRL" => Some(Self::Ctrl),
+            "ALT" => Some(Self::Alt),
+            "SHIFT" => Some(Self::Shift),
+            "META" => Some(Self::Meta),
+            _ => None,
+        }
+    }
+
+    /// Returns the physical keys that satisfy this modifier
+    pub fn keys(&self) -> [KeyCode; 2] {
+        match self {
+            Self::Ctrl => [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL],
+            Self::Alt => [KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT],
+            Self::Shift => [KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT],
+            Self::Meta => [KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA],
+        }
+    }
+}
+
+impl std::fmt::Display for Modifier {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::Ctrl => "CTRL",
+            Self::Alt => "ALT",
+            Self::Shift => "SHIFT",
+            Self::Meta => "META",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+/// A key name as written in the config file.
+/// It is resolved into a KeyCode by KeyResolver once the
+/// whole file has been parsed, so that errors can report
+/// where in the file the name appears.
+trait KeyName {
+    fn name(&self) -> &str;
+    /// The byte offset of the name within the file, if the
+    /// format keeps track of it
+    fn offset(&self) -> Option<usize>;
+}
+
+impl KeyName for String {
+    fn name(&self) -> &str {
+        self
+    }
+
+    fn offset(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl KeyName for toml::Spanned<String> {
+    fn name(&self) -> &str {
+        self.get_ref()
+    }
+
+    fn offset(&self) -> Option<usize> {
+        Some(self.start())
     }
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ConfigError {
-    #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
-    InvalidKey(String),
-    #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
-    ImpossibleParseKey,
-}
-
-impl std::convert::TryFrom<String> for KeyCodeWrapper {
-    type Error = ConfigError;
-    fn try_from(s: String) -> Result<KeyCodeWrapper, Self::Error> {
-        match EventCode::from_str(&EventType::EV_KEY, &s) {
-            Some(code) => match code {
-                EventCode::EV_KEY(code) => Ok(KeyCodeWrapper { code }),
-                _ => Err(ConfigError::ImpossibleParseKey),
-            },
-            None => Err(ConfigError::InvalidKey(s)),
+    #[error(
+        "Invalid key `{key}` for {location} in {file}{line_col}.{suggestions}  \
+         Use `evremap list-keys` to see possible keys."
+    )]
+    InvalidKey {
+        key: String,
+        location: String,
+        file: String,
+        line_col: String,
+        suggestions: String,
+    },
+    #[error(
+        "`{key}` for {location} in {file} is an alias for {count} keys, \
+         but only a single key may be used here"
+    )]
+    NotSingleKey {
+        key: String,
+        location: String,
+        file: String,
+        count: usize,
+    },
+    #[error(
+        "Invalid switch `{switch}` for {location} in {file}.  \
+         Possible switches are {possible}."
+    )]
+    InvalidSwitch {
+        switch: String,
+        location: String,
+        file: String,
+        possible: String,
+    },
+    #[error("`{option}` cannot be used for {location} in {file} because {reason}")]
+    InvalidOption {
+        option: String,
+        location: String,
+        file: String,
+        reason: String,
+    },
+    #[error("Invalid capability `{capability}` for {location} in {file}: {reason}.")]
+    InvalidCapability {
+        capability: String,
+        location: String,
+        file: String,
+        reason: String,
+    },
+    #[error("Invalid alias `{alias}` in {file}: it is already the name of {meaning}.")]
+    InvalidAlias {
+        alias: String,
+        file: String,
+        meaning: String,
+    },
+    #[error("Invalid LED `{led}` for {location} in {file}.  Possible LEDs are {possible}.")]
+    InvalidLed {
+        led: String,
+        location: String,
+        file: String,
+        possible: String,
+    },
+}
+
+/// Returns the names of all of the possible KEY_XXX and BTN_XXX values
+pub fn all_key_names() -> Vec<String> {
+    let mut keys: Vec<String> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
+        .iter()
+        .filter_map(|code| match code {
+            EventCode::EV_KEY(_) => Some(format!("{}", code)),
+            _ => None,
+        })
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Parses a key name.  In addition to the canonical `KEY_CAPSLOCK`
+/// form, the name is matched case insensitively and the `KEY_`
+/// prefix may be omitted, so `CapsLock` and `capslock` are accepted.
+pub fn parse_key(name: &str) -> Option<KeyCode> {
+    let upper = name.to_uppercase();
+    let candidates = [name.to_string(), upper.clone(), format!("KEY_{}", upper)];
+    for candidate in &candidates {
+        if let Some(EventCode::EV_KEY(code)) = EventCode::from_str(&EventType::EV_KEY, candidate) {
+            return Some(code);
+        }
+    }
+    None
+}
+
+/// Parses the name of an event code, such as `REL_WHEEL` or `BTN_LEFT`.
+/// Keys may also be named in any of the ways that `parse_key` accepts.
+pub fn parse_event_code(name: &str) -> Option<EventCode> {
+    if let Some(key) = parse_key(name) {
+        return Some(EventCode::EV_KEY(key));
+    }
+    let upper = name.to_uppercase();
+    let prefix = upper.split('_').next()?;
+    let event_type = EventType::from_str(&format!("EV_{}", prefix))?;
+    EventCode::from_str(&event_type, &upper)
+}
+
+/// Returns the names of all of the possible LED_XXX values
+pub fn all_led_names() -> Vec<String> {
+    let mut leds: Vec<String> = EventCode::EV_LED(LedCode::LED_NUML)
+        .iter()
+        .filter_map(|code| match code {
+            EventCode::EV_LED(LedCode::LED_MAX) => None,
+            EventCode::EV_LED(_) => Some(format!("{}", code)),
+            _ => None,
+        })
+        .collect();
+    leds.sort();
+    leds
+}
+
+/// Parses an LED name, such as `LED_SCROLLL`, with the same
+/// leniency as `parse_key`
+pub fn parse_led(name: &str) -> Option<LedCode> {
+    let upper = name.to_uppercase();
+    let candidates = [name.to_string(), upper.clone(), format!("LED_{}", upper)];
+    for candidate in &candidates {
+        if let Some(EventCode::EV_LED(code)) = EventCode::from_str(&EventType::EV_LED, candidate) {
+            return Some(code);
+        }
+    }
+    None
+}
+
+/// Returns the names of all of the possible SW_XXX values
+pub fn all_switch_names() -> Vec<String> {
+    let mut switches: Vec<String> = EventCode::EV_SW(SwitchCode::SW_LID)
+        .iter()
+        .filter_map(|code| match code {
+            EventCode::EV_SW(_) => Some(format!("{}", code)),
+            _ => None,
+        })
+        .collect();
+    switches.sort();
+    switches
+}
+
+/// Parses a switch name, such as `SW_TABLET_MODE`, with the same
+/// leniency as `parse_key`
+pub fn parse_switch(name: &str) -> Option<SwitchCode> {
+    let upper = name.to_uppercase();
+    let candidates = [name.to_string(), upper.clone(), format!("SW_{}", upper)];
+    for candidate in &candidates {
+        if let Some(EventCode::EV_SW(code)) = EventCode::from_str(&EventType::EV_SW, candidate) {
+            return Some(code);
+        }
+    }
+    None
+}
+
+/// Computes the Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current.push((prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1));
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+/// Returns the key names that are most similar to `name`
+fn suggest_keys(name: &str) -> Vec<String> {
+    let upper = name.to_uppercase();
+    let name = if upper.starts_with("KEY_") || upper.starts_with("BTN_") {
+        upper
+    } else {
+        format!("KEY_{}", upper)
+    };
+    let max_distance = (name.len() / 3).max(1);
+
+    let mut scored: Vec<(usize, String)> = all_key_names()
+        .into_iter()
+        .map(|candidate| (edit_distance(&name, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    scored.sort();
+    // Only suggest the closest matches
+    let best = scored.first().map(|(distance, _)| *distance);
+    scored
+        .into_iter()
+        .take_while(|(distance, _)| Some(*distance) == best)
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Identifies where a key name appears within the config,
+/// for example `remap[3].output[1]` or `aliases.hyper[0]`
+struct KeyLocation<'a> {
+    /// The table containing the field; empty for top level fields
+    table: &'a str,
+    /// The position of the table within an array of tables
+    index: Option<usize>,
+    field: &'a str,
+    /// The position within the field, if the field is a list
+    element: Option<usize>,
+}
+
+impl<'a> std::fmt::Display for KeyLocation<'a> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.table.is_empty() {
+            write!(fmt, "{}", self.table)?;
+            if let Some(index) = self.index {
+                write!(fmt, "[{}]", index)?;
+            }
+            write!(fmt, ".")?;
+        }
+        write!(fmt, "{}", self.field)?;
+        if let Some(element) = self.element {
+            write!(fmt, "[{}]", element)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the key names in the config file into KeyCodes
+struct KeyResolver<'a> {
+    path: &'a Path,
+    source: &'a str,
+    aliases: &'a HashMap<String, Vec<KeyCode>>,
+}
+
+impl<'a> KeyResolver<'a> {
+    /// Resolves a key name, expanding it if it is an alias
+    fn resolve(
+        &self,
+        key: &impl KeyName,
+        location: &KeyLocation,
+    ) -> Result<Vec<KeyCode>, ConfigError> {
+        if let Some(keys) = self.aliases.get(key.name()) {
+            return Ok(keys.clone());
+        }
+        if let Some(code) = parse_key(key.name()) {
+            return Ok(vec![code]);
         }
+
+        let line_col = match key.offset().map(|offset| self.line_col(offset)) {
+            Some((line, col)) => format!(" at line {} column {}", line, col),
+            None => String::new(),
+        };
+        let suggestions = suggest_keys(key.name());
+        let suggestions = if suggestions.is_empty() {
+            String::new()
+        } else {
+            format!("  Did you mean `{}`?", suggestions.join("`, `"))
+        };
+
+        Err(ConfigError::InvalidKey {
+            key: key.name().to_string(),
+            location: location.to_string(),
+            file: self.path.display().to_string(),
+            line_col,
+            suggestions,
+        })
+    }
+
+    /// Resolves a key name that must refer to exactly one key
+    fn resolve_one(
+        &self,
+        key: &impl KeyName,
+        location: &KeyLocation,
+    ) -> Result<KeyCode, ConfigError> {
+        let mut keys = self.resolve(key, location)?;
+        if keys.len() != 1 {
+            return Err(ConfigError::NotSingleKey {
+                key: key.name().to_string(),
+                location: location.to_string(),
+                file: self.path.display().to_string(),
+                count: keys.len(),
+            });
+        }
+        Ok(keys.remove(0))
+    }
+
+    /// Returns the 1-based line and column of `offset` within the file
+    fn line_col(&self, offset: usize) -> (usize, usize) {
+        let before = &self.source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line = before.matches('\n').count() + 1;
+        let col = before[line_start..].chars().count() + 1;
+        (line, col)
+    }
+
+    fn resolve_list(
+        &self,
+        keys: &[impl KeyName],
+        table: &str,
+        index: Option<usize>,
+        field: &str,
+    ) -> Result<Vec<KeyCode>, ConfigError> {
+        let mut codes = vec![];
+        for (element, key) in keys.iter().enumerate() {
+            codes.extend(self.resolve(
+                key,
+                &KeyLocation {
+                    table,
+                    index,
+                    field,
+                    element: Some(element),
+                },
+            )?);
+        }
+        Ok(codes)
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct DualRoleConfig {
-    input: KeyCodeWrapper,
-    hold: Vec<KeyCodeWrapper>,
-    tap: Vec<KeyCodeWrapper>,
+struct DualRoleConfig<K> {
+    #[serde(default)]
+    name: Option<String>,
+    input: K,
+    hold: Vec<K>,
+    tap: Vec<K>,
+    #[serde(default)]
+    when: ConditionConfig,
+}
+
+impl<K: KeyName> DualRoleConfig<K> {
+    fn resolve(self, index: usize, resolver: &KeyResolver) -> Result<NamedMapping, ConfigError> {
+        let mapping = Mapping::DualRole {
+            input: resolver.resolve_one(
+                &self.input,
+                &KeyLocation {
+                    table: "dual_role",
+                    index: Some(index),
+                    field: "input",
+                    element: None,
+                },
+            )?,
+            hold: resolver.resolve_list(&self.hold, "dual_role", Some(index), "hold")?,
+            tap: resolver.resolve_list(&self.tap, "dual_role", Some(index), "tap")?,
+            conditions: self.when.resolve("dual_role", index, resolver.path)?,
+        };
+        Ok(NamedMapping {
+            name: self.name,
+            mapping,
+        })
+    }
 }
 
-impl Into<Mapping> for DualRoleConfig {
-    fn into(self) -> Mapping {
-        Mapping::DualRole {
-            input: self.input.into(),
-            hold: self.hold.into_iter().map(Into::into).collect(),
-            tap: self.tap.into_iter().map(Into::into).collect(),
+#[derive(Debug, Deserialize)]
+// serde would otherwise require K: Default for the defaulted fields
+#[serde(bound(deserialize = "K: Deserialize<'de>"))]
+struct RemapConfig<K> {
+    #[serde(default)]
+    name: Option<String>,
+    input: Vec<K>,
+    output: Vec<K>,
+    #[serde(default)]
+    preserve_modifiers: Vec<K>,
+    #[serde(default)]
+    suppress_modifiers: Vec<K>,
+    #[serde(default)]
+    add_modifiers: Vec<K>,
+    #[serde(default)]
+    when: ConditionConfig,
+}
+
+impl<K: KeyName> RemapConfig<K> {
+    fn resolve(self, index: usize, resolver: &KeyResolver) -> Result<NamedMapping, ConfigError> {
+        // The input may contain side agnostic modifiers as well as keys
+        let mut input = HashSet::new();
+        let mut modifiers = HashSet::new();
+        for (element, key) in self.input.iter().enumerate() {
+            match Modifier::from_name(key.name()) {
+                Some(modifier) => {
+                    modifiers.insert(modifier);
+                }
+                None => input.extend(resolver.resolve(
+                    key,
+                    &KeyLocation {
+                        table: "remap",
+                        index: Some(index),
+                        field: "input",
+                        element: Some(element),
+                    },
+                )?),
+            }
         }
+
+        let modifier_options = ModifierOptions {
+            preserve: resolve_modifier_list(
+                &self.preserve_modifiers,
+                index,
+                "preserve_modifiers",
+                resolver,
+                false,
+            )?,
+            suppress: resolve_modifier_list(
+                &self.suppress_modifiers,
+                index,
+                "suppress_modifiers",
+                resolver,
+                false,
+            )?,
+            add: resolve_modifier_list(
+                &self.add_modifiers,
+                index,
+                "add_modifiers",
+                resolver,
+                true,
+            )?,
+        };
+
+        let mapping = Mapping::Remap {
+            input,
+            modifiers,
+            output: resolver
+                .resolve_list(&self.output, "remap", Some(index), "output")?
+                .into_iter()
+                .collect(),
+            modifier_options,
+            conditions: self.when.resolve("remap", index, resolver.path)?,
+        };
+        Ok(NamedMapping {
+            name: self.name,
+            mapping,
+        })
+    }
+}
+
+/// The conditions under which a rule applies; all of them must be met
+#[derive(Debug, Default, Deserialize)]
+struct ConditionConfig {
+    #[serde(default)]
+    switch_on: Vec<String>,
+    #[serde(default)]
+    switch_off: Vec<String>,
+    #[serde(default)]
+    device_present: Vec<String>,
+    #[serde(default)]
+    device_absent: Vec<String>,
+}
+
+impl ConditionConfig {
+    fn resolve(
+        self,
+        table: &str,
+        index: usize,
+        path: &Path,
+    ) -> Result<Vec<Condition>, ConfigError> {
+        let mut conditions = vec![];
+        for (field, names, on) in [
+            ("switch_on", &self.switch_on, true),
+            ("switch_off", &self.switch_off, false),
+        ] {
+            for (element, name) in names.iter().enumerate() {
+                let switch = parse_switch(name).ok_or_else(|| ConfigError::InvalidSwitch {
+                    switch: name.clone(),
+                    location: format!("{}[{}].when.{}[{}]", table, index, field, element),
+                    file: path.display().to_string(),
+                    possible: all_switch_names().join(", "),
+                })?;
+                conditions.push(Condition::Switch { switch, on });
+            }
+        }
+        for (names, present) in [(self.device_present, true), (self.device_absent, false)] {
+            for name in names {
+                conditions.push(Condition::Device { name, present });
+            }
+        }
+        Ok(conditions)
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct RemapConfig {
-    input: Vec<KeyCodeWrapper>,
-    output: Vec<KeyCodeWrapper>,
+struct DisableConfig<K> {
+    #[serde(default)]
+    name: Option<String>,
+    input: Vec<K>,
+    #[serde(default)]
+    remove_capability: bool,
+    #[serde(default)]
+    when: ConditionConfig,
 }
 
-impl Into<Mapping> for RemapConfig {
-    fn into(self) -> Mapping {
-        Mapping::Remap {
-            input: self.input.into_iter().map(Into::into).collect(),
-            output: self.output.into_iter().map(Into::into).collect(),
+impl<K: KeyName> DisableConfig<K> {
+    fn resolve(self, index: usize, resolver: &KeyResolver) -> Result<NamedMapping, ConfigError> {
+        let mut input = HashSet::new();
+        let mut modifiers = HashSet::new();
+        for (element, key) in self.input.iter().enumerate() {
+            match Modifier::from_name(key.name()) {
+                Some(modifier) => {
+                    modifiers.insert(modifier);
+                }
+                None => input.extend(resolver.resolve(
+                    key,
+                    &KeyLocation {
+                        table: "disable",
+                        index: Some(index),
+                        field: "input",
+                        element: Some(element),
+                    },
+                )?),
+            }
+        }
+        let conditions = self.when.resolve("disable", index, resolver.path)?;
+
+        // The capabilities of the output device are fixed when it is
+        // created, so they can only be changed for a key that is
+        // always swallowed
+        if self.remove_capability {
+            let reason = if input.len() != 1 || !modifiers.is_empty() {
+                Some("the input is not a single key")
+            } else if !conditions.is_empty() {
+                Some("the rule has conditions")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(ConfigError::InvalidOption {
+                    option: "remove_capability".to_string(),
+                    location: format!("disable[{}]", index),
+                    file: resolver.path.display().to_string(),
+                    reason: reason.to_string(),
+                });
+            }
+        }
+
+        Ok(NamedMapping {
+            name: self.name,
+            mapping: Mapping::Disable {
+                input,
+                modifiers,
+                remove_capability: self.remove_capability,
+                conditions,
+            },
+        })
+    }
+}
+
+/// Resolves a list of modifiers for a remap rule.  Side agnostic
+/// modifiers expand to both sides, unless `left_only` is set, in
+/// which case they produce the left version of the key.
+fn resolve_modifier_list(
+    keys: &[impl KeyName],
+    index: usize,
+    field: &str,
+    resolver: &KeyResolver,
+    left_only: bool,
+) -> Result<HashSet<KeyCode>, ConfigError> {
+    let mut codes = HashSet::new();
+    for (element, key) in keys.iter().enumerate() {
+        match Modifier::from_name(key.name()) {
+            Some(modifier) if left_only => {
+                codes.insert(modifier.keys()[0].clone());
+            }
+            Some(modifier) => codes.extend(modifier.keys().iter().cloned()),
+            None => codes.extend(resolver.resolve(
+                key,
+                &KeyLocation {
+                    table: "remap",
+                    index: Some(index),
+                    field,
+                    element: Some(element),
+                },
+            )?),
+        }
+    }
+    Ok(codes)
+}
+
+#[derive(Debug, Deserialize)]
+struct LedConfig<K> {
+    led: String,
+    keys: Vec<K>,
+}
+
+impl<K: KeyName> LedConfig<K> {
+    fn resolve(
+        self,
+        index: usize,
+        path: &Path,
+        resolver: &KeyResolver,
+    ) -> Result<LedIndicator, ConfigError> {
+        let led = parse_led(&self.led).ok_or_else(|| ConfigError::InvalidLed {
+            led: self.led.clone(),
+            location: format!("led[{}].led", index),
+            file: path.display().to_string(),
+            possible: all_led_names().join(", "),
+        })?;
+        let keys = resolver.resolve_list(&self.keys, "led", Some(index), "keys")?;
+        Ok(LedIndicator {
+            led,
+            keys: keys.into_iter().collect(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TapTimingConfig {
+    #[serde(default)]
+    min_press_ms: u64,
+    #[serde(default)]
+    spacing_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputConfig {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    phys: Option<String>,
+    #[serde(default)]
+    bustype: Option<u16>,
+    #[serde(default)]
+    vendor: Option<u16>,
+    #[serde(default)]
+    product: Option<u16>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    #[serde(default)]
+    split_pointer: bool,
+    #[serde(default)]
+    pointer_name: Option<String>,
+}
+
+impl OutputConfig {
+    fn resolve(&self, path: &Path) -> Result<OutputDevice, ConfigError> {
+        let mut capabilities = vec![];
+        for (idx, name) in self.capabilities.iter().enumerate() {
+            let code = parse_event_code(name).ok_or_else(|| ConfigError::InvalidCapability {
+                capability: name.clone(),
+                location: format!("output.capabilities[{}]", idx),
+                file: path.display().to_string(),
+                reason: "it is not the name of an event code".to_string(),
+            })?;
+            // Absolute axes need a range, which we have no way to
+            // specify, and the other types aren't emitted by evremap
+            let supported = matches!(
+                code,
+                EventCode::EV_KEY(_)
+                    | EventCode::EV_REL(_)
+                    | EventCode::EV_MSC(_)
+                    | EventCode::EV_SW(_)
+                    | EventCode::EV_LED(_)
+                    | EventCode::EV_SND(_)
+            );
+            if !supported {
+                return Err(ConfigError::InvalidCapability {
+                    capability: name.clone(),
+                    location: format!("output.capabilities[{}]", idx),
+                    file: path.display().to_string(),
+                    reason: "only KEY, BTN, REL, MSC, SW, LED and SND codes can be added"
+                        .to_string(),
+                });
+            }
+            capabilities.push(code);
         }
+
+        if self.pointer_name.is_some() && !self.split_pointer {
+            return Err(ConfigError::InvalidOption {
+                option: "pointer_name".to_string(),
+                location: "output".to_string(),
+                file: path.display().to_string(),
+                reason: "there is no pointer device unless `split_pointer` is set".to_string(),
+            });
+        }
+
+        Ok(OutputDevice {
+            name: self.name.clone(),
+            phys: self.phys.clone(),
+            bustype: self.bustype,
+            vendor: self.vendor,
+            product: self.product,
+            capabilities,
+            split_pointer: self.split_pointer,
+            pointer_name: self.pointer_name.clone(),
+        })
     }
 }
 
+/// An alias may name either a single key or a group of keys.
+/// An untagged enum can't hold spanned values, so errors in
+/// aliases don't report a position.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AliasConfig {
+    Key(String),
+    Group(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum MatchMode {
+    /// Remap every device that looks like a keyboard
+    AllKeyboards,
+}
+
 #[derive(Debug, Deserialize)]
-struct ConfigFile {
-    device_name: String,
+// serde would otherwise require K: Default for the defaulted fields
+#[serde(bound(deserialize = "K: Deserialize<'de>"))]
+struct ConfigFile<K> {
+    /// Other config files whose rules are merged ahead of ours
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// The names of included rules that should be removed
+    #[serde(default)]
+    disable_rules: Vec<String>,
+    /// Names that can be used in place of a key, or a list of keys
+    #[serde(default)]
+    aliases: HashMap<String, AliasConfig>,
+    /// Replaces the default set of modifier keys
+    #[serde(default)]
+    modifier_keys: Option<Vec<K>>,
+
+    #[serde(default)]
+    device_name: Option<String>,
+    #[serde(default)]
+    device_name_regex: Option<String>,
+    #[serde(default)]
+    device_path: Option<PathBuf>,
     #[serde(default)]
     phys: Option<String>,
+    #[serde(default)]
+    uniq: Option<String>,
+    #[serde(default)]
+    bustype: Option<u16>,
+    #[serde(default)]
+    vendor: Option<u16>,
+    #[serde(default)]
+    product: Option<u16>,
+    #[serde(default)]
+    version: Option<u16>,
+    #[serde(default, rename = "match")]
+    match_mode: Option<MatchMode>,
+
+    /// LEDs that evremap lights while particular keys are held
+    #[serde(default)]
+    led: Vec<LedConfig<K>>,
+
+    /// Spaces out the key events of dual role taps
+    #[serde(default)]
+    tap_timing: Option<TapTimingConfig>,
+
+    /// Properties of the virtual output device
+    #[serde(default)]
+    output: Option<OutputConfig>,
 
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    dual_role: Vec<DualRoleConfig<K>>,
 
+    /// Keys and chords that are swallowed
     #[serde(default)]
-    remap: Vec<RemapConfig>,
+    disable: Vec<DisableConfig<K>>,
+
+    #[serde(default)]
+    remap: Vec<RemapConfig<K>>,
+}
+
+impl<K> ConfigFile<K> {
+    fn device_match(&self) -> anyhow::Result<DeviceMatch> {
+        let name = match (&self.device_name, &self.device_name_regex) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of device_name and device_name_regex may be specified")
+            }
+            (Some(name), None) => Some(NameMatch::from_name(name.clone())),
+            (None, Some(re)) => Some(NameMatch::Regex(
+                Regex::new(re).context(format!("parsing device_name_regex `{}`", re))?,
+            )),
+            (None, None) => None,
+        };
+
+        Ok(DeviceMatch {
+            name,
+            phys: self.phys.clone(),
+            uniq: self.uniq.clone(),
+            bustype: self.bustype,
+            vendor: self.vendor,
+            product: self.product,
+            version: self.version,
+            path: self.device_path.clone(),
+            all_keyboards: self.match_mode == Some(MatchMode::AllKeyboards),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_toml(data: &str) -> anyhow::Result<LoadedFile> {
+        let config_file: ConfigFile<toml::Spanned<String>> = toml::from_str(data)?;
+        LoadedFile::resolve(Path::new("test.toml"), data, config_file, &mut vec![])
+    }
+
+    fn load_json(data: &str) -> anyhow::Result<LoadedFile> {
+        let config_file: ConfigFile<String> = serde_json::from_str(data)?;
+        LoadedFile::resolve(Path::new("test.json"), data, config_file, &mut vec![])
+    }
+
+    /// Writes `files` into a new directory and returns its path
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evremap-{}-{}", test, std::process::id()));
+        for (name, data) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        dir
+    }
+
+    fn remap(name: Option<&str>, input: KeyCode, output: KeyCode) -> NamedMapping {
+        NamedMapping {
+            name: name.map(str::to_string),
+            mapping: Mapping::remap([input], [output]).build(),
+        }
+    }
+
+    fn names(rules: &[NamedMapping]) -> Vec<Option<&str>> {
+        rules.iter().map(|r| r.name.as_deref()).collect()
+    }
+
+    #[test]
+    fn merge_replaces_named_rules_in_place() {
+        let mut rules = vec![
+            remap(Some("a"), KeyCode::KEY_A, KeyCode::KEY_B),
+            remap(None, KeyCode::KEY_C, KeyCode::KEY_D),
+            remap(Some("e"), KeyCode::KEY_E, KeyCode::KEY_F),
+        ];
+        merge_rules(
+            &mut rules,
+            vec![
+                remap(Some("a"), KeyCode::KEY_A, KeyCode::KEY_Z),
+                remap(None, KeyCode::KEY_C, KeyCode::KEY_D),
+                remap(Some("g"), KeyCode::KEY_G, KeyCode::KEY_H),
+            ],
+        );
+        assert_eq!(
+            names(&rules),
+            vec![Some("a"), None, Some("e"), None, Some("g")]
+        );
+        assert_eq!(
+            rules[0].mapping,
+            Mapping::remap([KeyCode::KEY_A], [KeyCode::KEY_Z]).build()
+        );
+    }
+
+    #[test]
+    fn includes_are_merged() {
+        let dir = write_files(
+            "includes",
+            &[
+                (
+                    "base.toml",
+                    r#"
+device_name = "Base Keyboard"
+modifier_keys = ["KEY_LEFTCTRL"]
+
+[aliases]
+nav = "KEY_CAPSLOCK"
+
+[[remap]]
+name = "left"
+input = ["KEY_LEFTALT", "KEY_LEFT"]
+output = ["KEY_HOME"]
+
+[[remap]]
+name = "right"
+input = ["KEY_LEFTALT", "KEY_RIGHT"]
+output = ["KEY_END"]
+
+[[remap]]
+input = ["KEY_F1"]
+output = ["KEY_MUTE"]
+"#,
+                ),
+                (
+                    "sub/top.json",
+                    r#"{
+  "include": ["../base.toml"],
+  "disable_rules": ["right"],
+  "remap": [
+    {"name": "left", "input": ["nav", "KEY_LEFT"], "output": ["KEY_HOME"]},
+    {"input": ["KEY_F2"], "output": ["KEY_VOLUMEUP"]}
+  ]
+}"#,
+                ),
+            ],
+        );
+        let loaded = LoadedFile::load(&dir.join("sub/top.json"), &mut vec![]).unwrap();
+        assert_eq!(
+            loaded.device.name.unwrap().to_string(),
+            "name=`Base Keyboard`"
+        );
+        assert_eq!(
+            loaded.modifier_keys,
+            Some([KeyCode::KEY_LEFTCTRL].iter().cloned().collect())
+        );
+        assert_eq!(names(&loaded.remap), vec![Some("left"), None, None]);
+        assert_eq!(
+            loaded.remap[0].mapping,
+            Mapping::remap(
+                [KeyCode::KEY_CAPSLOCK, KeyCode::KEY_LEFT],
+                [KeyCode::KEY_HOME]
+            )
+            .build()
+        );
+        assert_eq!(
+            loaded.remap[2].mapping,
+            Mapping::remap([KeyCode::KEY_F2], [KeyCode::KEY_VOLUMEUP]).build()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn including_file_selects_the_device() {
+        let dir = write_files(
+            "device",
+            &[
+                ("base.toml", "device_name = \"Base\"\n"),
+                ("top.toml", "include = [\"base.toml\"]\nphys = \"usb-1\"\n"),
+            ],
+        );
+        let loaded = LoadedFile::load(&dir.join("top.toml"), &mut vec![]).unwrap();
+        assert!(loaded.device.name.is_none());
+        assert_eq!(loaded.device.phys.as_deref(), Some("usb-1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_errors() {
+        let dir = write_files(
+            "include-errors",
+            &[
+                ("a.toml", "include = [\"b.toml\"]\n"),
+                ("b.toml", "include = [\"a.toml\"]\n"),
+                ("c.toml", "disable_rules = [\"missing\"]\n"),
+            ],
+        );
+        let err = LoadedFile::load(&dir.join("a.toml"), &mut vec![])
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("a.toml includes itself"));
+        let err = LoadedFile::load(&dir.join("c.toml"), &mut vec![])
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .ends_with("disable_rules refers to `missing` but there is no rule with that name"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn aliases() {
+        let data = r#"
+device_name = "Keyboard"
+
+[aliases]
+hyper = ["KEY_LEFTCTRL", "KEY_LEFTALT"]
+nav = "KEY_CAPSLOCK"
+
+[[remap]]
+input = ["nav", "KEY_H"]
+output = ["hyper", "KEY_H"]
+"#;
+        let loaded = load_toml(data).unwrap();
+        assert_eq!(
+            loaded.remap[0].mapping,
+            Mapping::remap(
+                [KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H],
+                [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTALT, KeyCode::KEY_H]
+            )
+            .build()
+        );
+    }
+
+    #[test]
+    fn aliases_cannot_chain() {
+        let data = r#"
+device_name = "Keyboard"
+
+[aliases]
+nav = "KEY_CAPSLOCK"
+other = "nav"
+"#;
+        let err = load_toml(data).err().unwrap().to_string();
+        assert!(err.starts_with("Invalid key `nav` for aliases.other in test.toml"));
+    }
+
+    #[test]
+    fn aliases_cannot_shadow_keys() {
+        for (name, meaning) in [
+            ("esc", "the key KEY_ESC"),
+            ("KEY_A", "the key KEY_A"),
+            ("Ctrl", "the modifier CTRL"),
+        ] {
+            let data = format!(
+                "device_name = \"Keyboard\"\n[aliases]\n{} = \"KEY_B\"\n",
+                name
+            );
+            let err = load_toml(&data).err().unwrap().to_string();
+            assert_eq!(
+                err,
+                format!(
+                    "Invalid alias `{}` in test.toml: it is already the name of {}.",
+                    name, meaning
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn modifier_keys_include_dual_role_holds() {
+        let dir = write_files(
+            "modifier-keys",
+            &[(
+                "config.toml",
+                r#"
+device_name = "Keyboard"
+modifier_keys = ["KEY_LEFTCTRL", "KEY_COMPOSE"]
+
+[[dual_role]]
+input = "KEY_CAPSLOCK"
+hold = ["KEY_F13"]
+tap = ["KEY_ESC"]
+"#,
+            )],
+        );
+        let config = MappingConfig::from_file(dir.join("config.toml")).unwrap();
+        assert_eq!(
+            config.modifier_keys,
+            [
+                KeyCode::KEY_LEFTCTRL,
+                KeyCode::KEY_COMPOSE,
+                KeyCode::KEY_F13
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn builders() {
+        let config = MappingConfig::new(DeviceMatch::by_name("Keyboard"))
+            .with_mapping(Mapping::dual_role(
+                KeyCode::KEY_CAPSLOCK,
+                [KeyCode::KEY_F13],
+                [KeyCode::KEY_ESC],
+            ))
+            .with_mapping(
+                Mapping::remap([KeyCode::KEY_LEFT], [KeyCode::KEY_HOME])
+                    .modifiers([Modifier::Alt])
+                    .when(Condition::switch(SwitchCode::SW_TABLET_MODE, false)),
+            )
+            .with_output(
+                OutputDevice::default()
+                    .with_name("Remapped")
+                    .with_ids(0x3, 0x1234, 0x5678),
+            );
+
+        assert!(config.modifier_keys().contains(&KeyCode::KEY_F13));
+        assert_eq!(config.mappings().len(), 2);
+        assert_eq!(
+            config.mappings()[1].conditions(),
+            &[Condition::Switch {
+                switch: SwitchCode::SW_TABLET_MODE,
+                on: false
+            }]
+        );
+        assert_eq!(config.output().name.as_deref(), Some("Remapped"));
+        assert_eq!(config.output().vendor, Some(0x1234));
+        assert_eq!(config.tap_timing(), TapTiming::default());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("KEY_A", "KEY_A"), 0);
+        assert_eq!(edit_distance("KEY_A", ""), 5);
+        assert_eq!(edit_distance("KEY_ESC", "KEY_ECS"), 2);
+        assert_eq!(edit_distance("KEY_CAPSLCK", "KEY_CAPSLOCK"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(suggest_keys("KEY_CAPSLCK"), vec!["KEY_CAPSLOCK"]);
+        // The KEY_ prefix and the case are optional, as for parse_key
+        assert_eq!(suggest_keys("capslck"), vec!["KEY_CAPSLOCK"]);
+        assert_eq!(suggest_keys("KEY_LEFTCRTL"), vec!["KEY_LEFTCTRL"]);
+        assert!(suggest_keys("KEY_NOTHING_LIKE_A_KEY").is_empty());
+    }
+
+    #[test]
+    fn parse_key_names() {
+        assert_eq!(parse_key("KEY_A"), Some(KeyCode::KEY_A));
+        assert_eq!(parse_key("key_a"), Some(KeyCode::KEY_A));
+        assert_eq!(parse_key("a"), Some(KeyCode::KEY_A));
+        assert_eq!(parse_key("CapsLock"), Some(KeyCode::KEY_CAPSLOCK));
+        assert_eq!(parse_key("BTN_LEFT"), Some(KeyCode::BTN_LEFT));
+        assert_eq!(parse_key("KEY_CAPSLCK"), None);
+        assert_eq!(parse_key("REL_WHEEL"), None);
+    }
+
+    #[test]
+    fn invalid_key_location() {
+        let data = r#"
+device_name = "Keyboard"
+
+[[remap]]
+input = ["KEY_A"]
+output = ["KEY_B"]
+
+[[remap]]
+input = ["KEY_LEFTCTRL", "KEY_CAPSLCK"]
+output = ["KEY_ESC"]
+"#;
+        let err = load_toml(data).err().unwrap().to_string();
+        assert_eq!(
+            err,
+            "Invalid key `KEY_CAPSLCK` for remap[1].input[1] in test.toml \
+             at line 9 column 26.  Did you mean `KEY_CAPSLOCK`?  \
+             Use `evremap list-keys` to see possible keys."
+        );
+    }
+
+    #[test]
+    fn invalid_key_without_location() {
+        let data =
+            r#"{"device_name": "Keyboard", "remap": [{"input": ["KEY_A"], "output": ["KEY_BB"]}]}"#;
+        let err = load_json(data).err().unwrap().to_string();
+        assert_eq!(
+            err,
+            "Invalid key `KEY_BB` for remap[0].output[0] in test.json.  \
+             Did you mean `KEY_AB`, `KEY_B`?  \
+             Use `evremap list-keys` to see possible keys."
+        );
+    }
 }
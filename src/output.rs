@@ -0,0 +1,360 @@
+use crate::deviceinfo::virtual_device_phys;
+use anyhow::{anyhow, bail, Context, Result};
+use evdev_rs::enums::{int_to_input_prop, EventCode, InputProp, EV_KEY as KeyCode, EV_MSC, EV_SYN};
+use evdev_rs::util::{event_code_to_int, int_to_event_code};
+use evdev_rs::{Device, InputEvent, TimeVal, UInputDevice};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+/// Which of the output events a virtual device receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum SinkKind {
+    /// Everything; used when the output isn't split
+    All,
+    /// Everything other than the pointer events
+    Keyboard,
+    /// Relative and absolute motion, buttons and touches
+    Pointer,
+}
+
+impl SinkKind {
+    pub fn accepts(self, code: &EventCode) -> bool {
+        match self {
+            Self::All => true,
+            Self::Keyboard => !is_pointer_event(code),
+            Self::Pointer => is_pointer_event(code),
+        }
+    }
+}
+
+/// Returns true for the events that belong to a pointing device,
+/// which libinput would rather not see on a keyboard
+fn is_pointer_event(code: &EventCode) -> bool {
+    match code {
+        EventCode::EV_REL(_) | EventCode::EV_ABS(_) => true,
+        EventCode::EV_SYN(EV_SYN::SYN_MT_REPORT) | EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP) => true,
+        // The mouse buttons, and the tool, touch and stylus buttons of
+        // tablets and touchpads.  Joystick and gamepad buttons aren't
+        // pointer buttons.
+        EventCode::EV_KEY(key) => {
+            let key = key.clone() as u32;
+            (KeyCode::BTN_LEFT as u32..=KeyCode::BTN_TASK as u32).contains(&key)
+                || (KeyCode::BTN_TOOL_PEN as u32..=KeyCode::BTN_TOOL_QUADTAP as u32).contains(&key)
+        }
+        _ => false,
+    }
+}
+
+/// A description of a virtual device, derived from an input device
+#[derive(Debug)]
+pub(crate) struct DeviceSpec {
+    name: String,
+    phys: Option<String>,
+    uniq: Option<String>,
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+    codes: Vec<CodeSpec>,
+    /// The input properties, such as INPUT_PROP_POINTER
+    properties: Vec<u32>,
+}
+
+#[derive(Debug)]
+struct CodeSpec {
+    event_type: u32,
+    code: u32,
+    /// The value, minimum, maximum, fuzz, flat and resolution of an
+    /// absolute axis, or the value of an EV_REP code
+    data: Vec<i32>,
+}
+
+impl DeviceSpec {
+    /// Describes a device named `name` with the identifiers of `input`,
+    /// and those of its capabilities that `kind` accepts.
+    /// Returns None if `input` has none of those capabilities.
+    pub fn from_device(input: &Device, kind: SinkKind, name: &str) -> Result<Option<Self>> {
+        let mut codes = vec![];
+        for code in EventCode::EV_SYN(EV_SYN::SYN_REPORT).iter() {
+            if !input.has_event_code(&code) || !kind.accepts(&code) {
+                continue;
+            }
+            let data = match code {
+                EventCode::EV_SYN(_) => continue,
+                EventCode::EV_ABS(_) => {
+                    let abs = input
+                        .abs_info(&code)
+                        .ok_or_else(|| anyhow!("no range for {}", code))?;
+                    vec![
+                        abs.value,
+                        abs.minimum,
+                        abs.maximum,
+                        abs.fuzz,
+                        abs.flat,
+                        abs.resolution,
+                    ]
+                }
+                EventCode::EV_REP(_) => vec![input.event_value(&code).unwrap_or(0)],
+                _ => vec![],
+            };
+            let (event_type, code) = event_code_to_int(&code);
+            codes.push(CodeSpec {
+                event_type,
+                code,
+                data,
+            });
+        }
+        if codes.is_empty() {
+            return Ok(None);
+        }
+
+        // The properties describe pointing devices, such as a
+        // touchpad that is also a button
+        let mut properties = vec![];
+        if kind != SinkKind::Keyboard {
+            for prop in InputProp::INPUT_PROP_POINTER.iter() {
+                if input.has_property(&prop) {
+                    properties.push(prop as u32);
+                }
+            }
+        }
+
+        Ok(Some(Self {
+            name: name.to_string(),
+            phys: input.phys().map(str::to_string),
+            uniq: input.uniq().map(str::to_string),
+            bustype: input.bustype(),
+            vendor: input.vendor_id(),
+            product: input.product_id(),
+            version: input.version(),
+            codes,
+            properties,
+        }))
+    }
+
+    /// Creates the virtual device
+    pub fn create(&self) -> Result<UInputDevice> {
+        let device = Device::new().ok_or_else(|| anyhow!("failed to make new Device"))?;
+        device.set_name(&self.name);
+        // The phys is tagged here, so that whatever the device is
+        // derived from, we recognise it as one of ours
+        device.set_phys(&virtual_device_phys(self.phys.as_deref().unwrap_or("")));
+        if let Some(uniq) = &self.uniq {
+            device.set_uniq(uniq);
+        }
+        device.set_bustype(self.bustype);
+        device.set_vendor_id(self.vendor);
+        device.set_product_id(self.product);
+        device.set_version(self.version);
+
+        for spec in &self.codes {
+            let code = int_to_event_code(spec.event_type, spec.code);
+            let result = match (&code, spec.data.as_slice()) {
+                // evdev-rs passes libevdev a pointer to a temporary
+                // when given an AbsInfo, so give it the raw struct
+                (EventCode::EV_ABS(_), &[value, minimum, maximum, fuzz, flat, resolution]) => {
+                    let abs_info = libc::input_absinfo {
+                        value,
+                        minimum,
+                        maximum,
+                        fuzz,
+                        flat,
+                        resolution,
+                    };
+                    device.enable_event_code(&code, Some(&abs_info as &dyn Any))
+                }
+                (EventCode::EV_ABS(_), _) => bail!("no range for {}", code),
+                (EventCode::EV_REP(_), &[value]) => {
+                    device.enable_event_code(&code, Some(&value as &dyn Any))
+                }
+                _ => device.enable_event_code(&code, None),
+            };
+            result.context(format!("enable {}", code))?;
+        }
+        for prop in &self.properties {
+            if let Some(prop) = int_to_input_prop(*prop) {
+                device
+                    .enable_property(&prop)
+                    .context(format!("enable {:?}", prop))?;
+            }
+        }
+
+        Ok(UInputDevice::create_from_device(&device)?)
+    }
+}
+
+enum SinkDevice {
+    UInput(UInputDevice),
+    /// A uinput device that was created by an `evremap helper`,
+    /// which passed us its fd
+    Helper(File),
+}
+
+/// A virtual device along with the events that are routed to it
+pub(crate) struct Sink {
+    kind: SinkKind,
+    device: SinkDevice,
+}
+
+impl Sink {
+    /// Creates a virtual device with all of the capabilities of `input`
+    pub fn from_device(input: &Device) -> Result<Self> {
+        Ok(Self {
+            kind: SinkKind::All,
+            device: SinkDevice::UInput(UInputDevice::create_from_device(input)?),
+        })
+    }
+
+    pub fn from_spec(kind: SinkKind, spec: &DeviceSpec) -> Result<Self> {
+        Ok(Self {
+            kind,
+            device: SinkDevice::UInput(spec.create()?),
+        })
+    }
+
+    /// Uses the uinput fd that an `evremap helper` passed us
+    pub fn from_helper(kind: SinkKind, file: File) -> Self {
+        Self {
+            kind,
+            device: SinkDevice::Helper(file),
+        }
+    }
+
+    fn write_event(&self, event: &InputEvent) -> Result<()> {
+        match &self.device {
+            SinkDevice::UInput(device) => device.write_event(event)?,
+            SinkDevice::Helper(file) => {
+                let raw = event.as_raw();
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        &raw as *const libc::input_event as *const u8,
+                        std::mem::size_of::<libc::input_event>(),
+                    )
+                };
+                (&*file).write_all(bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        match &self.device {
+            SinkDevice::UInput(device) => device.fd().map(IntoRawFd::into_raw_fd),
+            SinkDevice::Helper(file) => Some(file.as_raw_fd()),
+        }
+    }
+}
+
+/// Where the events produced by the mapper are sent
+pub(crate) enum Output {
+    /// Emit the events via virtual uinput devices.  Each event is sent
+    /// to the sinks that accept it; the sinks don't overlap.
+    UInput(Vec<Sink>),
+    /// Print the key events to stdout rather than emitting them.
+    /// This is used by `evremap watch` to show the input events,
+    /// and, when `show_output` is true, what they are mapped to.
+    Watch { show_output: bool },
+}
+
+impl Output {
+    /// Writes the events of a frame, followed by a SYN_REPORT for
+    /// each of the devices that received any of them
+    pub fn write_frame(&self, events: &[InputEvent], time: &TimeVal) -> Result<()> {
+        match self {
+            Self::UInput(sinks) => {
+                for sink in sinks {
+                    let mut wrote = false;
+                    for event in events.iter().filter(|e| sink.kind.accepts(&e.event_code)) {
+                        sink.write_event(event)?;
+                        wrote = true;
+                    }
+                    if wrote {
+                        sink.write_event(&InputEvent::new(
+                            time,
+                            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+                            0,
+                        ))?;
+                    }
+                }
+            }
+            Self::Watch { show_output: true } => {
+                for event in events {
+                    if let EventCode::EV_KEY(_) = event.event_code {
+                        println!(
+                            "{}    => {} {}",
+                            format_time(&event.time),
+                            event.event_code,
+                            event.value
+                        );
+                    }
+                }
+            }
+            Self::Watch { show_output: false } => {}
+        }
+        Ok(())
+    }
+
+    /// The fd of the device that the system sets the keyboard LEDs of
+    pub fn led_fd(&self) -> Option<RawFd> {
+        match self {
+            Self::UInput(sinks) => sinks
+                .iter()
+                .find(|sink| sink.kind != SinkKind::Pointer)
+                .and_then(Sink::raw_fd),
+            Self::Watch { .. } => None,
+        }
+    }
+}
+
+pub(crate) fn format_time(time: &TimeVal) -> String {
+    format!("{}.{:06}", time.tv_sec, time.tv_usec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointer_events() {
+        for key in [
+            KeyCode::BTN_LEFT,
+            KeyCode::BTN_TASK,
+            KeyCode::BTN_TOOL_PEN,
+            KeyCode::BTN_TOUCH,
+            KeyCode::BTN_STYLUS,
+            KeyCode::BTN_STYLUS3,
+            KeyCode::BTN_TOOL_DOUBLETAP,
+            KeyCode::BTN_TOOL_QUADTAP,
+        ] {
+            assert!(
+                is_pointer_event(&EventCode::EV_KEY(key.clone())),
+                "{:?}",
+                key
+            );
+        }
+        for key in [
+            KeyCode::KEY_A,
+            KeyCode::BTN_0,
+            KeyCode::BTN_TRIGGER,
+            KeyCode::BTN_SOUTH,
+            KeyCode::BTN_THUMBR,
+            KeyCode::BTN_GEAR_UP,
+            KeyCode::BTN_DPAD_UP,
+            KeyCode::BTN_TRIGGER_HAPPY1,
+        ] {
+            assert!(
+                !is_pointer_event(&EventCode::EV_KEY(key.clone())),
+                "{:?}",
+                key
+            );
+        }
+        assert!(is_pointer_event(&EventCode::EV_REL(
+            evdev_rs::enums::EV_REL::REL_X
+        )));
+        assert!(!SinkKind::Keyboard.accepts(&EventCode::EV_KEY(KeyCode::BTN_LEFT)));
+        assert!(SinkKind::Keyboard.accepts(&EventCode::EV_KEY(KeyCode::BTN_SOUTH)));
+    }
+}
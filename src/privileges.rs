@@ -0,0 +1,411 @@
+//! Dropping the privileges that were needed to open the devices,
+//! before we start reading keystrokes from them
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct PrivilegeOpts {
+    /// Once the devices are open, switch to this user.  The group
+    /// defaults to the user's group.
+    #[structopt(long)]
+    user: Option<String>,
+
+    /// Once the devices are open, switch to this group
+    #[structopt(long)]
+    group: Option<String>,
+
+    /// Once the devices are open, only allow the system calls that
+    /// remapping needs; any other system call kills the process
+    #[structopt(long)]
+    seccomp: bool,
+}
+
+/// The AUDIT_ARCH_* value of the syscall ABI that we are built for
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// The offsets of the `nr` and `arch` fields of `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// The offset of the low half of argument `n` in `struct seccomp_data`.
+/// Both of the architectures that we support are little endian.
+const fn seccomp_data_arg(n: u32) -> u32 {
+    16 + 8 * n
+}
+
+/// The ioctl types of evdev and uinput, from linux/input.h and
+/// linux/uinput.h
+const EVDEV_IOCTL_TYPE: u32 = b'E' as u32;
+const UINPUT_IOCTL_TYPE: u32 = b'U' as u32;
+
+/// The flags that `openat` may not be called with
+const OPENAT_DENIED_FLAGS: u32 = (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u32;
+
+/// `struct __user_cap_header_struct` and `struct __user_cap_data_struct`
+/// from linux/capability.h
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// The syscalls made by the event loop, by the threads that track
+/// conditions, and by the standard library on their behalf
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_ppoll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    // Conditions scan /dev/input and sysfs for devices and switches
+    libc::SYS_close,
+    libc::SYS_getdents64,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_fcntl,
+    // Memory allocation
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads, timing and locking
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    // Signals, exiting and panicking
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigaction,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// The syscalls that are only allowed with certain arguments, and the
+/// checks of their arguments.  Each check returns, rather than falling
+/// through to the next syscall.
+fn argument_checks() -> Vec<(libc::c_long, Vec<libc::sock_filter>)> {
+    let allow = bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW);
+    let kill = bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS);
+    let load_arg = |n| bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, n);
+    let jeq = |k, jt, jf| bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, k, jt, jf);
+    vec![
+        // Conditions read the switches of devices that are connected
+        // later, and sysfs; nothing may be opened for writing
+        (
+            libc::SYS_openat,
+            vec![
+                load_arg(seccomp_data_arg(2)),
+                bpf_stmt(
+                    libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+                    OPENAT_DENIED_FLAGS,
+                ),
+                jeq(0, 0, 1),
+                allow,
+                kill,
+            ],
+        ),
+        // Only the evdev and uinput ioctls that libevdev makes
+        (
+            libc::SYS_ioctl,
+            vec![
+                load_arg(seccomp_data_arg(1)),
+                bpf_stmt(libc::BPF_ALU | libc::BPF_RSH | libc::BPF_K, 8),
+                bpf_stmt(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0xff),
+                jeq(EVDEV_IOCTL_TYPE, 1, 0),
+                jeq(UINPUT_IOCTL_TYPE, 0, 1),
+                allow,
+                kill,
+            ],
+        ),
+        // Pinging the systemd watchdog, which only sends on the socket
+        // that was connected beforehand, so no address is given
+        (
+            libc::SYS_sendto,
+            vec![
+                load_arg(seccomp_data_arg(4)),
+                jeq(0, 0, 3),
+                load_arg(seccomp_data_arg(4) + 4),
+                jeq(0, 0, 1),
+                allow,
+                kill,
+            ],
+        ),
+    ]
+}
+
+impl PrivilegeOpts {
+    /// Switches user and group and installs the seccomp filter, as
+    /// requested, and clears the capabilities of the calling thread.
+    /// This must be called once all of the devices have been opened.
+    /// Capabilities and no_new_privs belong to each thread, and are
+    /// inherited by the threads that it starts, so it must also be
+    /// called before any other thread is started.
+    pub fn apply(&self) -> Result<()> {
+        if self.user.is_none() && self.group.is_none() && !self.seccomp {
+            return Ok(());
+        }
+
+        let threads = std::fs::read_dir("/proc/self/task")
+            .context("listing our threads")?
+            .count();
+        if threads != 1 {
+            bail!(
+                "privileges can only be dropped by a single threaded process, \
+                 but there are {} threads",
+                threads
+            );
+        }
+
+        let user = match &self.user {
+            Some(user) => Some(lookup_user(user)?),
+            None => None,
+        };
+        let gid = match (&self.group, &user) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, Some((_, gid))) => Some(*gid),
+            (None, None) => None,
+        };
+
+        // This needs CAP_SETPCAP, so it happens while we still have it
+        drop_bounding_set()?;
+
+        if let Some(gid) = gid {
+            // glibc applies these to all of our threads
+            if unsafe { libc::setgroups(1, &gid) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("setgroups");
+            }
+            if unsafe { libc::setresgid(gid, gid, gid) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("setresgid");
+            }
+        }
+        if let Some((uid, _)) = user {
+            if unsafe { libc::setresuid(uid, uid, uid) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("setresuid");
+            }
+            // Make sure that root can't be regained
+            if uid != 0 && unsafe { libc::setresuid(0, 0, 0) } == 0 {
+                bail!("regained root after switching to uid {}", uid);
+            }
+        }
+        // Leaving root normally clears our capabilities, but not if
+        // SECBIT_KEEP_CAPS is set, and not at all if we stay root
+        clear_capabilities()?;
+
+        // Makes sure that an execve can't grant us privileges, and
+        // is required in order to install the filter without them
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("setting no_new_privs");
+        }
+
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if self.seccomp {
+            install_seccomp_filter()?;
+        }
+
+        log::info!(
+            "Dropped privileges: uid {}, gid {}{}",
+            uid,
+            gid,
+            if self.seccomp { ", seccomp filter" } else { "" }
+        );
+        Ok(())
+    }
+}
+
+fn capget() -> Result<[CapData; 2]> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("capget");
+    }
+    Ok(data)
+}
+
+/// Drops every capability from the bounding set, so that none can be
+/// regained, and clears the ambient set
+fn drop_bounding_set() -> Result<()> {
+    if unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    } != 0
+    {
+        let err = std::io::Error::last_os_error();
+        // Kernels older than 4.3 don't have an ambient set
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err).context("clearing the ambient capabilities");
+        }
+    }
+
+    // Without any capabilities we can't drop from the bounding set,
+    // but then there is nothing for it to let us keep either
+    let privileged = capget()?.iter().any(|data| data.permitted != 0);
+    // PR_CAPBSET_READ fails once we are past the last capability
+    let mut cap = 0;
+    while unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) } >= 0 {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } != 0 && privileged {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("dropping capability {} from the bounding set", cap));
+        }
+        cap += 1;
+    }
+    Ok(())
+}
+
+/// Clears the effective, permitted and inheritable capabilities
+fn clear_capabilities() -> Result<()> {
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("capset");
+    }
+    if capget()? != data {
+        bail!("capabilities remain after clearing them");
+    }
+    Ok(())
+}
+
+/// Returns the uid and the group of `user`
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("there is no user named `{}`", user);
+    }
+    Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) })
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("there is no group named `{}`", group);
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Installs a filter that kills the process when it makes a syscall
+/// that isn't in ALLOWED_SYSCALLS, or that uses a different ABI
+fn install_seccomp_filter() -> Result<()> {
+    let arch = match AUDIT_ARCH {
+        Some(arch) => arch,
+        None => bail!("--seccomp is not supported on this architecture"),
+    };
+
+    let mut filter = vec![
+        bpf_stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_ARCH,
+        ),
+        bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    for nr in ALLOWED_SYSCALLS {
+        filter.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *nr as u32,
+            0,
+            1,
+        ));
+        filter.push(bpf_stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ALLOW,
+        ));
+    }
+    // The checks overwrite the syscall number that was loaded, but
+    // they always return, so it is intact when a check is skipped
+    for (nr, check) in argument_checks() {
+        filter.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            nr as u32,
+            0,
+            check.len() as u8,
+        ));
+        filter.extend(check);
+    }
+    filter.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_KILL_PROCESS,
+    ));
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // TSYNC applies the filter to all of our threads, and fails with
+    // the id of a thread that it couldn't be applied to
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        res if res < 0 => {
+            Err(std::io::Error::last_os_error()).context("installing the seccomp filter")
+        }
+        tid => bail!("unable to apply the seccomp filter to thread {}", tid),
+    }
+}
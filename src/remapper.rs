@@ -1,10 +1,17 @@
+use crate::conditions::ConditionState;
+use crate::deviceinfo::{open_device, virtual_device_phys, VIRTUAL_DEVICE_NAME_PREFIX};
+use crate::helper::HelperConnection;
 use crate::mapping::*;
-use anyhow::*;
-use evdev_rs::{Device, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice};
+use crate::output::{format_time, DeviceSpec, Output, Sink, SinkKind};
+use crate::systemd::Heartbeat;
+use anyhow::{anyhow, Context, Result};
+use evdev_rs::enums::EV_SYN;
+use evdev_rs::{Device, GrabMode, InputEvent, LedState, ReadFlag, TimeVal};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::io::{IntoRawFd, RawFd};
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy, Debug)]
 enum KeyEventType {
@@ -48,21 +55,69 @@ fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     Duration::from_micros(((secs * MICROS_PER_SECOND) + usecs) as u64)
 }
 
+fn timeval_add(time: &TimeVal, duration: Duration) -> TimeVal {
+    const MICROS_PER_SECOND: libc::c_long = 1000000;
+    let usecs = time.tv_usec + duration.as_micros() as libc::c_long;
+    TimeVal::new(
+        time.tv_sec + usecs / MICROS_PER_SECOND,
+        usecs % MICROS_PER_SECOND,
+    )
+}
+
 pub struct InputMapper {
     input: Device,
-    output: UInputDevice,
+    output: Output,
     /// If present in this map, the key is down since the instant
     /// of its associated value
     input_state: HashMap<KeyCode, TimeVal>,
 
     mappings: Vec<Mapping>,
+    /// The state that the conditions of the mappings are evaluated against
+    conditions: ConditionState,
+
+    /// The keys that are treated as modifiers
+    modifier_keys: HashSet<KeyCode>,
 
     /// The most recent candidate for a tap function is held here
     tapping: Option<KeyCode>,
+    /// How the key events of a tap are spaced out
+    tap_timing: TapTiming,
+    /// Tap events that are waiting to be emitted, in the order
+    /// of the instants that they are due
+    scheduled: VecDeque<(Instant, InputEvent)>,
 
     output_keys: HashSet<KeyCode>,
+
+    /// The events that make up the next frame of the output device.
+    /// They are written together, followed by a single SYN_REPORT,
+    /// so that touchpad and tablet frames stay coherent.
+    frame: Vec<InputEvent>,
+    /// True while the events of an input frame are being read; the
+    /// output frame is then ended by the SYN_REPORT of the input
+    in_frame: bool,
+
+    /// LEDs on the input device that are driven by the held keys,
+    /// rather than by the state of the output device
+    leds: Vec<LedIndicator>,
+    /// The driven LEDs that are currently lit
+    lit_leds: HashSet<LedCode>,
+
+    /// The connection to the `evremap helper` that opened the input
+    /// device and created the output devices.  They are released
+    /// when it is closed.
+    _helper: Option<HelperConnection>,
+
+    /// Tells the systemd watchdog that the event loop is running
+    heartbeat: Option<Heartbeat>,
 }
 
+// SAFETY: the libevdev and uinput devices aren't Send only because they
+// hold raw pointers.  libevdev doesn't tie them to the thread that made
+// them, and the mapper owns them outright, so it can be moved to another
+// thread.  This lets the mappers be made before privileges are dropped,
+// and only then be given threads of their own.
+unsafe impl Send for InputMapper {}
+
 fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
     input
         .enable(&EventCode::EV_KEY(key.clone()))
@@ -71,116 +126,517 @@ fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
 }
 
 impl InputMapper {
-    pub fn create_mapper<P: AsRef<Path>>(path: P, mappings: Vec<Mapping>) -> Result<Self> {
-        let path = path.as_ref();
-        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
-        let mut input = Device::new().ok_or_else(|| anyhow!("failed to make new Device"))?;
-        input
-            .set_fd(f)
-            .context(format!("assigning fd for {} to Device", path.display()))?;
+    pub fn create_mapper<P: AsRef<Path>>(path: P, config: MappingConfig) -> Result<Self> {
+        Self::create(path.as_ref(), config, None)
+    }
+
+    /// Like `create_mapper`, but the input device is opened and grabbed,
+    /// and the output devices are created, by the `evremap helper`
+    /// listening on `socket`, so that we don't need access to them
+    pub fn create_mapper_via_helper<P: AsRef<Path>>(
+        socket: &Path,
+        path: P,
+        config: MappingConfig,
+    ) -> Result<Self> {
+        let helper = HelperConnection::connect(socket)?;
+        Self::create(path.as_ref(), config, Some(helper))
+    }
 
-        input.set_name(&format!("evremap Virtual input for {}", path.display()));
+    fn create(
+        path: &Path,
+        config: MappingConfig,
+        helper: Option<HelperConnection>,
+    ) -> Result<Self> {
+        let mut input = match &helper {
+            Some(helper) => {
+                let mut input =
+                    Device::new().ok_or_else(|| anyhow!("failed to make new Device"))?;
+                input
+                    .set_fd(helper.open_device(path)?)
+                    .context(format!("assigning fd for {} to Device", path.display()))?;
+                input
+            }
+            None => open_device(path, true)?,
+        };
 
         // Ensure that any remapped keys are supported by the generated output device
-        for map in &mappings {
+        let output_device = &config.output;
+        let mut produced = HashSet::new();
+        let mut removed = vec![];
+        for map in &config.mappings {
             match map {
                 Mapping::DualRole { tap, hold, .. } => {
-                    for t in tap {
-                        enable_key_code(&mut input, t.clone())?;
+                    produced.extend(tap.iter().chain(hold).cloned());
+                }
+                Mapping::Remap {
+                    output,
+                    modifier_options,
+                    ..
+                } => {
+                    produced.extend(output.iter().chain(&modifier_options.add).cloned());
+                }
+                Mapping::Disable {
+                    input,
+                    remove_capability: true,
+                    ..
+                } => removed.extend(input.iter().cloned()),
+                Mapping::Disable { .. } => {}
+            }
+        }
+        removed.retain(|key| {
+            if produced.contains(key) {
+                log::warn!(
+                    "Not removing {:?} from the output device because another mapping produces it",
+                    key
+                );
+                return false;
+            }
+            true
+        });
+
+        if helper.is_some() {
+            // The helper derives the output devices from the input
+            // device itself, so it only takes the keys that we add
+            if output_device.name.is_some()
+                || output_device.phys.is_some()
+                || output_device.bustype.is_some()
+                || output_device.vendor.is_some()
+                || output_device.product.is_some()
+                || output_device.pointer_name.is_some()
+            {
+                log::warn!(
+                    "The name, phys, ids and pointer_name of [output] are ignored \
+                     when the output devices are created by evremap helper"
+                );
+            }
+            for code in &output_device.capabilities {
+                match code {
+                    EventCode::EV_KEY(key) => {
+                        produced.insert(key.clone());
                     }
-                    for h in hold {
-                        enable_key_code(&mut input, h.clone())?;
+                    code => log::warn!(
+                        "Ignoring the [output] capability {} because only keys can be \
+                         added by evremap helper",
+                        code
+                    ),
+                }
+            }
+        } else {
+            // The output device is created from the input device, so
+            // it is given the properties that the output should have
+            match &output_device.name {
+                Some(name) => input.set_name(name),
+                None => input.set_name(&format!(
+                    "{} for {}",
+                    VIRTUAL_DEVICE_NAME_PREFIX,
+                    path.display()
+                )),
+            }
+            // The phys is always tagged so that we can recognise the
+            // device as one of ours, whatever it is named
+            let phys = match &output_device.phys {
+                Some(phys) => phys.clone(),
+                None => input.phys().unwrap_or("").to_string(),
+            };
+            input.set_phys(&virtual_device_phys(&phys));
+            if let Some(bustype) = output_device.bustype {
+                input.set_bustype(bustype);
+            }
+            if let Some(vendor) = output_device.vendor {
+                input.set_vendor_id(vendor);
+            }
+            if let Some(product) = output_device.product {
+                input.set_product_id(product);
+            }
+            for code in &output_device.capabilities {
+                input
+                    .enable(code)
+                    .context(format!("enable capability {}", code))?;
+            }
+        }
+
+        for key in &produced {
+            enable_key_code(&mut input, key.clone())?;
+        }
+        // Removing a key from the input device also removes it from the
+        // output device, and stops libevdev from reporting it to us
+        for key in &removed {
+            input
+                .disable(&EventCode::EV_KEY(key.clone()))
+                .context(format!("disable key {:?}", key))?;
+        }
+
+        let context = || format!("creating UInputDevices from {}", path.display());
+        let kinds: &[SinkKind] = if output_device.split_pointer {
+            &[SinkKind::Keyboard, SinkKind::Pointer]
+        } else {
+            &[SinkKind::All]
+        };
+        let sinks = match &helper {
+            Some(helper) => {
+                let mut sinks = vec![];
+                for &kind in kinds {
+                    if let Some(file) = helper
+                        .create_output(path, kind, &produced, &removed)
+                        .with_context(context)?
+                    {
+                        sinks.push(Sink::from_helper(kind, file));
                     }
                 }
-                Mapping::Remap { output, .. } => {
-                    for o in output {
-                        enable_key_code(&mut input, o.clone())?;
+                sinks
+            }
+            None if output_device.split_pointer => {
+                let name = input.name().unwrap_or("").to_string();
+                let pointer_name = output_device
+                    .pointer_name
+                    .clone()
+                    .unwrap_or_else(|| format!("{} pointer", name));
+                let mut sinks = vec![];
+                for (kind, name) in [
+                    (SinkKind::Keyboard, name),
+                    (SinkKind::Pointer, pointer_name),
+                ] {
+                    if let Some(spec) =
+                        DeviceSpec::from_device(&input, kind, &name).with_context(context)?
+                    {
+                        sinks.push(Sink::from_spec(kind, &spec).with_context(context)?);
                     }
                 }
+                sinks
             }
+            None => vec![Sink::from_device(&input)
+                .context(format!("creating UInputDevice from {}", path.display()))?],
+        };
+
+        // The helper grabs the device before passing it to us
+        if helper.is_none() {
+            input
+                .grab(GrabMode::Grab)
+                .context(format!("grabbing exclusive access on {}", path.display()))?;
+        }
+
+        let mapper = Self {
+            input,
+            output: Output::UInput(sinks),
+            input_state: HashMap::new(),
+            output_keys: HashSet::new(),
+            frame: vec![],
+            in_frame: false,
+            tapping: None,
+            tap_timing: config.tap_timing,
+            scheduled: VecDeque::new(),
+            conditions: ConditionState::start(&config.mappings),
+            mappings: config.mappings,
+            modifier_keys: config.modifier_keys,
+            leds: config.leds,
+            lit_leds: HashSet::new(),
+            _helper: helper,
+            heartbeat: Heartbeat::from_env(),
+        };
+
+        // Start with the driven LEDs off, as no keys are held yet
+        for led in mapper.driven_leds() {
+            mapper.set_led(&led, false, &TimeVal::new(0, 0));
         }
 
-        let output = UInputDevice::create_from_device(&input)
-            .context(format!("creating UInputDevice from {}", path.display()))?;
+        Ok(mapper)
+    }
 
-        input
-            .grab(GrabMode::Grab)
-            .context(format!("grabbing exclusive access on {}", path.display()))?;
+    /// Opens the device without grabbing it, and without creating an
+    /// output device.  When the mapper is run, the key events from the
+    /// device are printed, along with the keys that `config` would
+    /// produce if `config` is specified.
+    pub fn create_watcher<P: AsRef<Path>>(path: P, config: Option<MappingConfig>) -> Result<Self> {
+        let input = open_device(path.as_ref(), false)?;
+        let show_output = config.is_some();
+        let (mappings, modifier_keys, leds, tap_timing) = match config {
+            Some(config) => (
+                config.mappings,
+                config.modifier_keys,
+                config.leds,
+                config.tap_timing,
+            ),
+            None => (
+                vec![],
+                default_modifier_keys(),
+                vec![],
+                TapTiming::default(),
+            ),
+        };
 
         Ok(Self {
             input,
-            output,
+            output: Output::Watch { show_output },
             input_state: HashMap::new(),
             output_keys: HashSet::new(),
+            frame: vec![],
+            in_frame: false,
             tapping: None,
+            tap_timing,
+            scheduled: VecDeque::new(),
+            conditions: ConditionState::start(&mappings),
             mappings,
+            modifier_keys,
+            leds,
+            lit_leds: HashSet::new(),
+            _helper: None,
+            heartbeat: None,
         })
     }
 
     pub fn run_mapper(&mut self) -> Result<()> {
+        self.conditions.refresh_in_background();
+        let input_fd = self
+            .input
+            .fd()
+            .map(IntoRawFd::into_raw_fd)
+            .ok_or_else(|| anyhow!("input device has no fd"))?;
+        // The LED changes that the system makes to the output device
+        // are read from its uinput fd, so that they can be applied
+        // to the input device
+        let output_fd = self.output.led_fd();
+
         log::info!("Going into read loop");
         loop {
+            self.wait_for_input(input_fd, output_fd)?;
             let (status, event) = self
                 .input
                 .next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
             match status {
-                evdev_rs::ReadStatus::Success => {
-                    if let EventCode::EV_KEY(ref key) = event.event_code {
+                evdev_rs::ReadStatus::Success => match event.event_code {
+                    EventCode::EV_KEY(ref key) => {
                         log::trace!("IN {:?}", event);
+                        if let Output::Watch { .. } = self.output {
+                            println!(
+                                "{} {} {}",
+                                format_time(&event.time),
+                                event.event_code,
+                                event.value
+                            );
+                        }
+                        self.in_frame = true;
                         self.update_with_event(&event, key.clone())?;
-                    } else {
+                    }
+                    EventCode::EV_SYN(EV_SYN::SYN_REPORT) => {
+                        self.in_frame = false;
+                        self.flush_frame(&event.time)?;
+                    }
+                    _ => {
                         log::trace!("PASSTHRU {:?}", event);
-                        self.output.write_event(&event)?;
+                        self.in_frame = true;
+                        self.frame.push(event);
                     }
+                },
+                evdev_rs::ReadStatus::Sync => {
+                    log::warn!("SYN_DROPPED: resyncing with device state");
+                    self.resync(&event.time)?;
                 }
-                evdev_rs::ReadStatus::Sync => bail!("ReadStatus::Sync!"),
             }
         }
     }
 
-    /// Compute the effective set of keys that are pressed
-    fn compute_keys(&self) -> HashSet<KeyCode> {
-        // Start with the input keys
-        let mut keys: HashSet<KeyCode> = self.input_state.keys().cloned().collect();
-
-        // First phase is to apply any DualRole mappings as they are likely to
-        // be used to produce modifiers when held.
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, hold, .. } = map {
-                if keys.contains(input) {
-                    keys.remove(input);
-                    for h in hold {
-                        keys.insert(h.clone());
-                    }
+    /// Waits until the input device has events to read.  Meanwhile,
+    /// scheduled tap events are emitted as they fall due, any LED
+    /// changes that are made to the output device are forwarded, and
+    /// the systemd watchdog is told that the loop is running.
+    /// Between input frames, no input is read until all of the
+    /// scheduled events have been emitted, so that the keys that
+    /// follow a tap can't be emitted in the middle of it.
+    fn wait_for_input(&mut self, input_fd: RawFd, output_fd: Option<RawFd>) -> Result<()> {
+        loop {
+            if let Some(heartbeat) = &mut self.heartbeat {
+                heartbeat.beat_if_due();
+            }
+            if !self.in_frame {
+                self.emit_scheduled()?;
+            }
+            let reading = self.in_frame || self.scheduled.is_empty();
+            if reading && self.input.has_event_pending() {
+                return Ok(());
+            }
+
+            let mut deadline = match self.scheduled.front() {
+                Some((due, _)) if !self.in_frame => Some(*due),
+                _ => None,
+            };
+            if let Some(heartbeat) = &self.heartbeat {
+                let ping = heartbeat.next_beat();
+                deadline = Some(deadline.map_or(ping, |due| due.min(ping)));
+            }
+            let timeout = match deadline {
+                Some(due) => {
+                    // Round up, so that we don't wake before it is due
+                    let wait = due.saturating_duration_since(Instant::now());
+                    wait.as_micros().div_ceil(1000) as libc::c_int
+                }
+                None => -1,
+            };
+            // poll ignores negative fds
+            let mut fds = [
+                libc::pollfd {
+                    fd: if reading { input_fd } else { -1 },
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: output_fd.unwrap_or(-1),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
                 }
+                return Err(err).context("polling the input and output devices");
+            }
+            if let Some(output_fd) = output_fd {
+                if fds[1].revents & libc::POLLIN != 0 {
+                    self.forward_led_event(output_fd)?;
+                }
+            }
+            // Errors are reported by reading from the input device
+            if fds[0].revents != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads an event that the system wrote to the output device, and
+    /// applies it to the input device if it changes an LED that isn't
+    /// driven by the held keys
+    fn forward_led_event(&mut self, output_fd: RawFd) -> Result<()> {
+        let mut raw: libc::input_event = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::input_event>();
+        let res = unsafe { libc::read(output_fd, &mut raw as *mut _ as *mut libc::c_void, size) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted
+                || err.kind() == std::io::ErrorKind::WouldBlock
+            {
+                return Ok(());
             }
+            return Err(err).context("reading from the output device");
+        }
+        if res as usize != size {
+            return Ok(());
         }
 
-        let mut keys_minus_remapped = keys.clone();
+        if let EventCode::EV_LED(led) =
+            evdev_rs::util::int_to_event_code(raw.type_ as u32, raw.code as u32)
+        {
+            if !self.leds.iter().any(|i| i.led == led) {
+                let time = TimeVal::new(raw.time.tv_sec, raw.time.tv_usec);
+                log::trace!("LED {:?} {}", led, raw.value);
+                self.set_led(&led, raw.value != 0, &time);
+            }
+        }
+        Ok(())
+    }
 
-        // Second pass to apply Remap items
-        for map in &self.mappings {
-            if let Mapping::Remap { input, output } = map {
-                if input.is_subset(&keys_minus_remapped) {
-                    for i in input {
-                        keys.remove(i);
-                        if !is_modifier(i) {
-                            keys_minus_remapped.remove(i);
-                        }
-                    }
-                    for o in output {
-                        keys.insert(o.clone());
-                        // Outputs that apply are not visible as
-                        // inputs for later remap rules
-                        if !is_modifier(o) {
-                            keys_minus_remapped.remove(o);
-                        }
+    /// The LEDs that are driven by the held keys
+    fn driven_leds(&self) -> HashSet<LedCode> {
+        self.leds.iter().map(|i| i.led.clone()).collect()
+    }
+
+    /// Lights, or extinguishes, the driven LEDs according to the keys
+    /// that are held.  An LED that is listed by several indicators is
+    /// lit while any of them match.
+    fn update_leds(&mut self, time: &TimeVal) {
+        let lit: HashSet<LedCode> = self
+            .leds
+            .iter()
+            .filter(|i| i.keys.iter().all(|k| self.input_state.contains_key(k)))
+            .map(|i| i.led.clone())
+            .collect();
+        for led in self.driven_leds() {
+            let on = lit.contains(&led);
+            if on != self.lit_leds.contains(&led) {
+                self.set_led(&led, on, time);
+            }
+        }
+        self.lit_leds = lit;
+    }
+
+    /// Turns an LED on the input device on or off.  Failing to do so
+    /// isn't fatal, as the keyboard remains usable.
+    fn set_led(&self, led: &LedCode, on: bool, time: &TimeVal) {
+        match self.output {
+            Output::UInput(_) => {
+                let state = if on { LedState::On } else { LedState::Off };
+                if let Err(err) = self
+                    .input
+                    .kernel_set_led_value(&EventCode::EV_LED(led.clone()), state)
+                {
+                    log::warn!("Failed to set {:?}: {}", led, err);
+                }
+            }
+            Output::Watch { show_output: true } => {
+                println!("{}    => {:?} {}", format_time(time), led, on as i32);
+            }
+            Output::Watch { show_output: false } => {}
+        }
+    }
+
+    /// Called after libevdev reports SYN_DROPPED.
+    /// Drain the delta events that describe how the device state
+    /// changed while events were being dropped, reconcile our model
+    /// of the pressed keys and then bring the output device in line
+    /// with it.
+    fn resync(&mut self, time: &TimeVal) -> Result<()> {
+        let mut time = time.clone();
+        // The rest of the interrupted input frame was dropped, so the
+        // part that we have is discarded.  Key events are kept, as
+        // they are already reflected in `output_keys`.
+        self.frame
+            .retain(|event| matches!(event.event_code, EventCode::EV_KEY(_)));
+        self.in_frame = false;
+        loop {
+            match self.input.next_event(ReadFlag::SYNC) {
+                Ok((evdev_rs::ReadStatus::Sync, event)) => {
+                    log::trace!("SYNC {:?}", event);
+                    match event.event_code {
+                        EventCode::EV_KEY(ref key) => match KeyEventType::from_value(event.value) {
+                            KeyEventType::Release => {
+                                self.input_state.remove(key);
+                            }
+                            KeyEventType::Press | KeyEventType::Repeat => {
+                                self.input_state
+                                    .entry(key.clone())
+                                    .or_insert_with(|| event.time.clone());
+                            }
+                            KeyEventType::Unknown(_) => {}
+                        },
+                        EventCode::EV_SYN(_) => {}
+                        _ => self.frame.push(event.clone()),
                     }
+                    time = event.time;
                 }
+                // libevdev signals the end of the delta with -EAGAIN
+                Err(err) if err as i32 == libc::EAGAIN => break,
+                Err(err) => return Err(err.into()),
+                Ok((evdev_rs::ReadStatus::Success, _)) => break,
             }
         }
 
-        keys
+        // Anything that was pending may have been released while
+        // events were dropped; it's not safe to treat it as a tap
+        self.cancel_pending_tap();
+        self.update_leds(&time);
+        self.compute_and_apply_keys(&time)?;
+        self.generate_sync_event(&time)
+    }
+
+    /// Compute the effective set of keys that are pressed
+    fn compute_keys(&self) -> HashSet<KeyCode> {
+        let mappings: Vec<&Mapping> = self.active_mappings().collect();
+        apply_mappings(
+            self.input_state.keys().cloned().collect(),
+            &mappings,
+            &self.modifier_keys,
+        )
     }
 
     /// Compute the difference between our desired set of keys
@@ -209,18 +665,25 @@ impl InputMapper {
             .collect();
 
         if !to_release.is_empty() {
-            to_release.sort_by(modifiers_last);
+            to_release.sort_by(|a, b| modifiers_last(&self.modifier_keys, a, b));
             self.emit_keys(&to_release, time, KeyEventType::Release)?;
         }
         if !to_press.is_empty() {
-            to_press.sort_by(modifiers_first);
+            to_press.sort_by(|a, b| modifiers_first(&self.modifier_keys, a, b));
             self.emit_keys(&to_press, time, KeyEventType::Press)?;
         }
         Ok(())
     }
 
+    /// The mappings whose conditions are currently met
+    fn active_mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings
+            .iter()
+            .filter(move |map| self.conditions.is_met(map.conditions()))
+    }
+
     fn lookup_dual_role_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        for map in &self.mappings {
+        for map in self.active_mappings() {
             if let Mapping::DualRole { input, .. } = map {
                 if *input == code {
                     // A DualRole mapping has the highest precedence
@@ -235,7 +698,7 @@ impl InputMapper {
     fn lookup_mapping(&self, code: KeyCode) -> Option<Mapping> {
         let mut candidates = vec![];
 
-        for map in &self.mappings {
+        for map in self.active_mappings() {
             match map {
                 Mapping::DualRole { input, .. } => {
                     if *input == code {
@@ -244,7 +707,12 @@ impl InputMapper {
                         return Some(map.clone());
                     }
                 }
-                Mapping::Remap { input, .. } => {
+                Mapping::Remap {
+                    input, modifiers, ..
+                }
+                | Mapping::Disable {
+                    input, modifiers, ..
+                } => {
                     // Look for a mapping that includes the current key.
                     // If part of a chord, all of its component keys must
                     // also be pressed.
@@ -258,6 +726,15 @@ impl InputMapper {
                             break;
                         }
                     }
+                    // Side agnostic modifiers are satisfied by either key
+                    for m in modifiers {
+                        let sides = m.keys();
+                        if sides.contains(&code) {
+                            code_matched = true;
+                        } else if !sides.iter().any(|k| self.input_state.contains_key(k)) {
+                            all_matched = false;
+                        }
+                    }
                     if code_matched && all_matched {
                         candidates.push(map);
                     }
@@ -265,19 +742,22 @@ impl InputMapper {
             }
         }
 
-        // Any matches must be Remap entries.  We want the one
-        // with the most active keys
-        candidates.sort_by(|a, b| match (a, b) {
-            (Mapping::Remap { input: input_a, .. }, Mapping::Remap { input: input_b, .. }) => {
-                input_a.len().cmp(&input_b.len()).reverse()
+        // Any matches must be Remap or Disable entries.  We want
+        // the one with the most active keys
+        candidates.sort_by_key(|m| match m {
+            Mapping::Remap {
+                input, modifiers, ..
             }
-            _ => unreachable!(),
+            | Mapping::Disable {
+                input, modifiers, ..
+            } => std::cmp::Reverse(input.len() + modifiers.len()),
+            Mapping::DualRole { .. } => unreachable!(),
         });
 
-        candidates.get(0).map(|&m| m.clone())
+        candidates.first().map(|&m| m.clone())
     }
 
-    pub fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+    fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
         let event_type = KeyEventType::from_value(event.value);
         match event_type {
             KeyEventType::Release => {
@@ -299,8 +779,7 @@ impl InputMapper {
                         if tapping == code
                             && timeval_diff(&event.time, &pressed_at) <= Duration::from_millis(200)
                         {
-                            self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
-                            self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
+                            self.emit_tap(&tap, &event.time)?;
                         }
                     }
                 }
@@ -329,6 +808,7 @@ impl InputMapper {
                         let output: Vec<KeyCode> = output.iter().cloned().collect();
                         self.emit_keys(&output, &event.time, KeyEventType::Repeat)?;
                     }
+                    Some(Mapping::Disable { .. }) => {}
                     None => {
                         // Just pass it through
                         self.cancel_pending_tap();
@@ -341,6 +821,7 @@ impl InputMapper {
             }
         }
 
+        self.update_leds(&event.time);
         Ok(())
     }
 
@@ -348,6 +829,35 @@ impl InputMapper {
         self.tapping.take();
     }
 
+    /// Presses and then releases the keys of a dual role tap.  When
+    /// tap timing is configured the events are scheduled, to be
+    /// emitted by `wait_for_input`, rather than emitted at once.
+    fn emit_tap(&mut self, tap: &[KeyCode], time: &TimeVal) -> Result<()> {
+        if self.tap_timing == TapTiming::default() {
+            self.emit_keys(tap, time, KeyEventType::Press)?;
+            return self.emit_keys(tap, time, KeyEventType::Release);
+        }
+
+        self.scheduled
+            .extend(schedule_tap(tap, time, self.tap_timing, Instant::now()));
+        Ok(())
+    }
+
+    /// Emits the scheduled tap events that are due, each in
+    /// a frame of its own
+    fn emit_scheduled(&mut self) -> Result<()> {
+        let now = Instant::now();
+        while let Some((due, _)) = self.scheduled.front() {
+            if *due > now {
+                break;
+            }
+            if let Some((_, event)) = self.scheduled.pop_front() {
+                self.write_event_and_sync(&event)?;
+            }
+        }
+        Ok(())
+    }
+
     fn emit_keys(
         &mut self,
         key: &[KeyCode],
@@ -370,8 +880,13 @@ impl InputMapper {
 
     fn write_event(&mut self, event: &InputEvent) -> Result<()> {
         log::trace!("OUT: {:?}", event);
-        self.output.write_event(&event)?;
         if let EventCode::EV_KEY(ref key) = event.event_code {
+            // A frame can only carry one value for each key, so a
+            // tap, which presses and releases its keys straight away,
+            // ends the frame early
+            if self.frame.iter().any(|e| e.event_code == event.event_code) {
+                self.flush_frame(&event.time)?;
+            }
             let event_type = KeyEventType::from_value(event.value);
             match event_type {
                 KeyEventType::Press | KeyEventType::Repeat => {
@@ -383,49 +898,199 @@ impl InputMapper {
                 _ => {}
             }
         }
+        self.frame.push(event.clone());
         Ok(())
     }
 
-    fn generate_sync_event(&self, time: &TimeVal) -> Result<()> {
-        self.output.write_event(&InputEvent::new(
-            time,
-            &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
-            0,
-        ))?;
+    /// Ends the current output frame.  Inside an input frame this is
+    /// deferred until the SYN_REPORT of the input, so that the keys
+    /// are emitted in the same frame as the events around them.
+    fn generate_sync_event(&mut self, time: &TimeVal) -> Result<()> {
+        if !self.in_frame {
+            self.flush_frame(time)?;
+        }
         Ok(())
     }
+
+    /// Writes the buffered frame to the output devices, followed by
+    /// a SYN_REPORT.  Nothing is written for an empty frame.
+    fn flush_frame(&mut self, time: &TimeVal) -> Result<()> {
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        let frame = std::mem::take(&mut self.frame);
+        self.output.write_frame(&frame, time)
+    }
 }
 
-fn make_event(key: KeyCode, time: &TimeVal, event_type: KeyEventType) -> InputEvent {
-    InputEvent::new(time, &EventCode::EV_KEY(key), event_type.value())
+/// Applies `mappings` to the `pressed` input keys, returning the keys
+/// that should be pressed on the output device
+fn apply_mappings(
+    pressed: HashSet<KeyCode>,
+    mappings: &[&Mapping],
+    modifier_keys: &HashSet<KeyCode>,
+) -> HashSet<KeyCode> {
+    // Start with the input keys
+    let mut keys = pressed;
+
+    // First phase is to apply any DualRole mappings as they are likely to
+    // be used to produce modifiers when held.
+    for map in mappings {
+        if let Mapping::DualRole { input, hold, .. } = map {
+            if keys.contains(input) {
+                keys.remove(input);
+                for h in hold {
+                    keys.insert(h.clone());
+                }
+            }
+        }
+    }
+
+    // Then swallow any disabled keys, so that remap rules
+    // don't see them either
+    for map in mappings {
+        if let Mapping::Disable {
+            input, modifiers, ..
+        } = map
+        {
+            let matched_modifiers = match match_modifiers(modifiers, &keys) {
+                Some(matched) => matched,
+                None => continue,
+            };
+            if input.is_subset(&keys) {
+                // Held modifiers are left alone, unless they are
+                // the only keys in the chord
+                let (swallowed, held): (Vec<&KeyCode>, Vec<&KeyCode>) =
+                    input.iter().partition(|k| !modifier_keys.contains(k));
+                if swallowed.is_empty() {
+                    for k in held.into_iter().chain(&matched_modifiers) {
+                        keys.remove(k);
+                    }
+                } else {
+                    for k in swallowed {
+                        keys.remove(k);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut keys_minus_remapped = keys.clone();
+
+    // Second pass to apply Remap items
+    for map in mappings {
+        if let Mapping::Remap {
+            input,
+            modifiers,
+            output,
+            modifier_options,
+            ..
+        } = map
+        {
+            let matched_modifiers = match match_modifiers(modifiers, &keys_minus_remapped) {
+                Some(matched) => matched,
+                None => continue,
+            };
+            if input.is_subset(&keys_minus_remapped) {
+                // Consume whichever physical keys satisfied the
+                // side agnostic modifiers
+                for m in &matched_modifiers {
+                    if !modifier_options.preserve.contains(m) {
+                        keys.remove(m);
+                    }
+                }
+                for i in input {
+                    if !modifier_options.preserve.contains(i) {
+                        keys.remove(i);
+                    }
+                    if !modifier_keys.contains(i) {
+                        keys_minus_remapped.remove(i);
+                    }
+                }
+                for s in &modifier_options.suppress {
+                    keys.remove(s);
+                }
+                for o in output.iter().chain(&modifier_options.add) {
+                    keys.insert(o.clone());
+                    // Outputs that apply are not visible as
+                    // inputs for later remap rules
+                    if !modifier_keys.contains(o) {
+                        keys_minus_remapped.remove(o);
+                    }
+                }
+            }
+        }
+    }
+
+    keys
 }
 
-fn is_modifier(key: &KeyCode) -> bool {
-    match key {
-        KeyCode::KEY_FN
-        | KeyCode::KEY_LEFTALT
-        | KeyCode::KEY_RIGHTALT
-        | KeyCode::KEY_LEFTMETA
-        | KeyCode::KEY_RIGHTMETA
-        | KeyCode::KEY_LEFTCTRL
-        | KeyCode::KEY_RIGHTCTRL
-        | KeyCode::KEY_LEFTSHIFT
-        | KeyCode::KEY_RIGHTSHIFT => true,
-        _ => false,
+/// Checks that each of the side agnostic `modifiers` is satisfied by
+/// a key in `keys`.  Returns the physical keys that satisfied them,
+/// or None if any modifier is not held.
+fn match_modifiers(modifiers: &HashSet<Modifier>, keys: &HashSet<KeyCode>) -> Option<Vec<KeyCode>> {
+    let mut matched = vec![];
+    for m in modifiers {
+        let held: Vec<KeyCode> = m
+            .keys()
+            .iter()
+            .filter(|k| keys.contains(k))
+            .cloned()
+            .collect();
+        if held.is_empty() {
+            return None;
+        }
+        matched.extend(held);
+    }
+    Some(matched)
+}
+
+/// Returns the events of a tap of `tap` that happens at `now`, and
+/// when each of them is due, spaced out according to `timing`
+fn schedule_tap(
+    tap: &[KeyCode],
+    time: &TimeVal,
+    timing: TapTiming,
+    now: Instant,
+) -> Vec<(Instant, InputEvent)> {
+    let mut offset = Duration::from_millis(0);
+    let events = tap
+        .iter()
+        .map(|k| (k, KeyEventType::Press))
+        .chain(tap.iter().map(|k| (k, KeyEventType::Release)));
+    let mut scheduled = vec![];
+    for (idx, (key, event_type)) in events.enumerate() {
+        if idx == tap.len() {
+            // The keys are held for at least `min_press`
+            offset += timing.min_press.max(timing.spacing);
+        } else if idx > 0 {
+            offset += timing.spacing;
+        }
+        // The synthesized events are stamped as though they had
+        // happened that long after the release of the dual role key
+        scheduled.push((
+            now + offset,
+            make_event(key.clone(), &timeval_add(time, offset), event_type),
+        ));
     }
+    scheduled
+}
+
+fn make_event(key: KeyCode, time: &TimeVal, event_type: KeyEventType) -> InputEvent {
+    InputEvent::new(time, &EventCode::EV_KEY(key), event_type.value())
 }
 
 /// Orders modifier keys ahead of non-modifier keys.
 /// Unfortunately the underlying type doesn't allow direct
 /// comparison, but that's ok for our purposes.
-fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
-    if is_modifier(a) {
-        if is_modifier(b) {
+fn modifiers_first(modifier_keys: &HashSet<KeyCode>, a: &KeyCode, b: &KeyCode) -> Ordering {
+    if modifier_keys.contains(a) {
+        if modifier_keys.contains(b) {
             Ordering::Equal
         } else {
             Ordering::Less
         }
-    } else if is_modifier(b) {
+    } else if modifier_keys.contains(b) {
         Ordering::Greater
     } else {
         // Neither are modifiers
@@ -433,6 +1098,321 @@ fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
     }
 }
 
-fn modifiers_last(a: &KeyCode, b: &KeyCode) -> Ordering {
-    modifiers_first(a, b).reverse()
+fn modifiers_last(modifier_keys: &HashSet<KeyCode>, a: &KeyCode, b: &KeyCode) -> Ordering {
+    modifiers_first(modifier_keys, a, b).reverse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(codes: &[KeyCode]) -> HashSet<KeyCode> {
+        codes.iter().cloned().collect()
+    }
+
+    fn apply(pressed: &[KeyCode], mappings: &[Mapping]) -> HashSet<KeyCode> {
+        apply_with_modifiers(pressed, mappings, &default_modifier_keys())
+    }
+
+    fn apply_with_modifiers(
+        pressed: &[KeyCode],
+        mappings: &[Mapping],
+        modifier_keys: &HashSet<KeyCode>,
+    ) -> HashSet<KeyCode> {
+        let mappings: Vec<&Mapping> = mappings.iter().collect();
+        apply_mappings(keys(pressed), &mappings, modifier_keys)
+    }
+
+    #[test]
+    fn timeval_add_carries_microseconds() {
+        let time = TimeVal::new(10, 999_900);
+        assert_eq!(
+            timeval_add(&time, Duration::from_micros(50)),
+            TimeVal::new(10, 999_950)
+        );
+        assert_eq!(
+            timeval_add(&time, Duration::from_micros(100)),
+            TimeVal::new(11, 0)
+        );
+        assert_eq!(
+            timeval_add(&time, Duration::from_millis(2_250)),
+            TimeVal::new(13, 249_900)
+        );
+    }
+
+    #[test]
+    fn tap_events_are_spaced_out() {
+        let time = TimeVal::new(10, 990_000);
+        let now = Instant::now();
+        let timing = TapTiming::new(Duration::from_millis(20), Duration::from_millis(5));
+        let scheduled = schedule_tap(
+            &[KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_9],
+            &time,
+            timing,
+            now,
+        );
+
+        let ms = Duration::from_millis;
+        let expected = [
+            (ms(0), KeyCode::KEY_LEFTSHIFT, KeyEventType::Press),
+            (ms(5), KeyCode::KEY_9, KeyEventType::Press),
+            // The keys are held for min_press after the last press
+            (ms(25), KeyCode::KEY_LEFTSHIFT, KeyEventType::Release),
+            (ms(30), KeyCode::KEY_9, KeyEventType::Release),
+        ];
+        assert_eq!(scheduled.len(), expected.len());
+        for ((due, event), (offset, key, event_type)) in scheduled.iter().zip(expected) {
+            assert_eq!(*due, now + offset);
+            assert_eq!(event.event_code, EventCode::EV_KEY(key));
+            assert_eq!(event.value, event_type.value());
+            assert_eq!(event.time, timeval_add(&time, offset));
+        }
+        // The release of KEY_9 crosses into the next second
+        assert_eq!(scheduled[3].1.time, TimeVal::new(11, 20_000));
+    }
+
+    #[test]
+    fn tap_is_held_for_at_least_the_spacing() {
+        let now = Instant::now();
+        let timing = TapTiming::new(Duration::from_millis(0), Duration::from_millis(5));
+        let scheduled = schedule_tap(&[KeyCode::KEY_ESC], &TimeVal::new(1, 0), timing, now);
+        let dues: Vec<Instant> = scheduled.iter().map(|(due, _)| *due).collect();
+        assert_eq!(dues, vec![now, now + Duration::from_millis(5)]);
+    }
+
+    #[test]
+    fn side_agnostic_modifiers_match_either_side() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_LEFT], [KeyCode::KEY_HOME])
+            .modifiers([Modifier::Alt])
+            .build()];
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT], &mappings),
+            keys(&[KeyCode::KEY_HOME])
+        );
+        assert_eq!(
+            apply(&[KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFT], &mappings),
+            keys(&[KeyCode::KEY_HOME])
+        );
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFT], &mappings),
+            keys(&[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFT])
+        );
+    }
+
+    #[test]
+    fn side_agnostic_modifiers_consume_the_matched_key() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_LEFT], [KeyCode::KEY_HOME])
+            .modifiers([Modifier::Alt])
+            .build()];
+        // Only the key that matched is consumed, other modifiers stay held
+        assert_eq!(
+            apply(
+                &[
+                    KeyCode::KEY_RIGHTALT,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_LEFT
+                ],
+                &mappings
+            ),
+            keys(&[KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_HOME])
+        );
+    }
+
+    #[test]
+    fn side_agnostic_modifiers_all_need_to_be_held() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_C], [KeyCode::KEY_COPY])
+            .modifiers([Modifier::Ctrl, Modifier::Shift])
+            .build()];
+        assert_eq!(
+            apply(&[KeyCode::KEY_RIGHTCTRL, KeyCode::KEY_C], &mappings),
+            keys(&[KeyCode::KEY_RIGHTCTRL, KeyCode::KEY_C])
+        );
+        assert_eq!(
+            apply(
+                &[
+                    KeyCode::KEY_RIGHTCTRL,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_C
+                ],
+                &mappings
+            ),
+            keys(&[KeyCode::KEY_COPY])
+        );
+    }
+
+    #[test]
+    fn other_held_modifiers_pass_through() {
+        let mappings = vec![Mapping::remap(
+            [KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT],
+            [KeyCode::KEY_HOME],
+        )
+        .build()];
+        assert_eq!(
+            apply(
+                &[
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFT
+                ],
+                &mappings
+            ),
+            keys(&[KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_HOME])
+        );
+    }
+
+    #[test]
+    fn preserved_modifiers_stay_held() {
+        let mappings = vec![Mapping::remap(
+            [KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT],
+            [KeyCode::KEY_HOME],
+        )
+        .preserve_modifiers([KeyCode::KEY_LEFTALT])
+        .build()];
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT], &mappings),
+            keys(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_HOME])
+        );
+    }
+
+    #[test]
+    fn suppressed_modifiers_are_released() {
+        let mappings = vec![Mapping::remap(
+            [KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFT],
+            [KeyCode::KEY_HOME],
+        )
+        .suppress_modifiers([KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT])
+        .build()];
+        assert_eq!(
+            apply(
+                &[
+                    KeyCode::KEY_RIGHTSHIFT,
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFT
+                ],
+                &mappings
+            ),
+            keys(&[KeyCode::KEY_HOME])
+        );
+    }
+
+    #[test]
+    fn added_modifiers_are_held_with_the_output() {
+        let mappings = vec![Mapping::remap([KeyCode::KEY_F3], [KeyCode::KEY_C])
+            .add_modifiers([KeyCode::KEY_LEFTCTRL])
+            .build()];
+        assert_eq!(
+            apply(&[KeyCode::KEY_F3], &mappings),
+            keys(&[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_C])
+        );
+        assert_eq!(
+            apply(&[KeyCode::KEY_F4], &mappings),
+            keys(&[KeyCode::KEY_F4])
+        );
+    }
+
+    #[test]
+    fn modifiers_stay_visible_to_later_rules() {
+        let mappings = vec![
+            Mapping::remap([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H], [KeyCode::KEY_LEFT]).build(),
+            Mapping::remap([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_J], [KeyCode::KEY_DOWN]).build(),
+        ];
+        let pressed = [KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H, KeyCode::KEY_J];
+
+        // CAPSLOCK isn't a modifier by default, so the first rule
+        // consumes it and the second can't match
+        assert_eq!(
+            apply(&pressed, &mappings),
+            keys(&[KeyCode::KEY_LEFT, KeyCode::KEY_J])
+        );
+
+        let mut modifier_keys = default_modifier_keys();
+        modifier_keys.insert(KeyCode::KEY_CAPSLOCK);
+        assert_eq!(
+            apply_with_modifiers(&pressed, &mappings, &modifier_keys),
+            keys(&[KeyCode::KEY_LEFT, KeyCode::KEY_DOWN])
+        );
+    }
+
+    #[test]
+    fn outputs_hide_keys_from_later_rules_unless_modifiers() {
+        let mappings = vec![
+            Mapping::remap([KeyCode::KEY_LEFTALT, KeyCode::KEY_F8], [KeyCode::KEY_F8]).build(),
+            Mapping::remap([KeyCode::KEY_F8], [KeyCode::KEY_MUTE]).build(),
+        ];
+        let pressed = [KeyCode::KEY_LEFTALT, KeyCode::KEY_F8];
+        assert_eq!(apply(&pressed, &mappings), keys(&[KeyCode::KEY_F8]));
+
+        let modifier_keys = keys(&[KeyCode::KEY_F8]);
+        assert_eq!(
+            apply_with_modifiers(&pressed, &mappings, &modifier_keys),
+            keys(&[KeyCode::KEY_MUTE])
+        );
+    }
+
+    #[test]
+    fn configured_modifiers_are_pressed_first() {
+        let modifier_keys = keys(&[KeyCode::KEY_CAPSLOCK]);
+        let mut to_press = [KeyCode::KEY_A, KeyCode::KEY_CAPSLOCK, KeyCode::KEY_LEFTCTRL];
+        to_press.sort_by(|a, b| modifiers_first(&modifier_keys, a, b));
+        assert_eq!(to_press[0], KeyCode::KEY_CAPSLOCK);
+        to_press.sort_by(|a, b| modifiers_last(&modifier_keys, a, b));
+        assert_eq!(to_press[2], KeyCode::KEY_CAPSLOCK);
+    }
+
+    #[test]
+    fn disabled_keys_are_swallowed() {
+        let mappings = vec![Mapping::disable([KeyCode::KEY_INSERT], false)];
+        assert_eq!(apply(&[KeyCode::KEY_INSERT], &mappings), keys(&[]));
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_INSERT], &mappings),
+            keys(&[KeyCode::KEY_LEFTSHIFT])
+        );
+    }
+
+    #[test]
+    fn disabled_chords_leave_modifiers_held() {
+        let mappings = vec![Mapping::disable(
+            [KeyCode::KEY_LEFTMETA, KeyCode::KEY_L],
+            false,
+        )];
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTMETA, KeyCode::KEY_L], &mappings),
+            keys(&[KeyCode::KEY_LEFTMETA])
+        );
+        // The chord has to be complete
+        assert_eq!(apply(&[KeyCode::KEY_L], &mappings), keys(&[KeyCode::KEY_L]));
+    }
+
+    #[test]
+    fn disabled_modifier_chords_are_swallowed() {
+        let mappings = vec![Mapping::Disable {
+            input: keys(&[KeyCode::KEY_LEFTMETA]),
+            modifiers: [Modifier::Alt].iter().cloned().collect(),
+            remove_capability: false,
+            conditions: vec![],
+        }];
+        assert_eq!(
+            apply(
+                &[KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTALT, KeyCode::KEY_A],
+                &mappings
+            ),
+            keys(&[KeyCode::KEY_A])
+        );
+    }
+
+    #[test]
+    fn disabled_keys_are_hidden_from_remap_rules() {
+        let mappings = vec![
+            Mapping::disable([KeyCode::KEY_INSERT], false),
+            Mapping::remap(
+                [KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_INSERT],
+                [KeyCode::KEY_PASTE],
+            )
+            .build(),
+        ];
+        assert_eq!(
+            apply(&[KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_INSERT], &mappings),
+            keys(&[KeyCode::KEY_LEFTSHIFT])
+        );
+    }
 }
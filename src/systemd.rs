@@ -0,0 +1,144 @@
+//! Support for running as a systemd service with `Type=notify`
+//! and `WatchdogSec=`
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The connection to $NOTIFY_SOCKET, which is made by `connect` or on
+/// first use and then kept, so that the watchdog can still be pinged
+/// once we are no longer allowed to make new connections
+static NOTIFY_SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+fn connect_notify_socket() -> Option<UnixDatagram> {
+    let path = std::env::var_os("NOTIFY_SOCKET")?;
+    let socket = UnixDatagram::unbound().ok()?;
+    let path = path.to_str()?;
+    let result = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.connect_addr(&addr))
+        }
+        None => socket.connect(path),
+    };
+    match result {
+        Ok(()) => Some(socket),
+        Err(err) => {
+            log::error!("Unable to connect to NOTIFY_SOCKET {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Connects to the service manager, if we were started by systemd.
+/// This must happen before privileges are dropped.
+pub fn connect() {
+    NOTIFY_SOCKET.get_or_init(connect_notify_socket);
+}
+
+/// Tells the service manager about a change of state, such as
+/// `READY=1`.  Does nothing when we weren't started by systemd.
+pub fn notify(state: &str) {
+    if let Some(socket) = NOTIFY_SOCKET.get_or_init(connect_notify_socket) {
+        if let Err(err) = socket.send(state.as_bytes()) {
+            log::error!("Unable to notify systemd of {}: {}", state, err);
+        }
+    }
+}
+
+/// The watchdog that was configured by `WatchdogSec=`, if there is one.
+/// It is shared by the event loops of all of the devices.
+static WATCHDOG: OnceLock<Option<Watchdog>> = OnceLock::new();
+
+/// Pings the service manager while all of the event loops are running,
+/// so that it can restart us if any of them gets stuck
+struct Watchdog {
+    interval: Duration,
+    state: Mutex<WatchdogState>,
+}
+
+struct WatchdogState {
+    last_ping: Instant,
+    /// Whether each event loop has beaten since the last ping, by the
+    /// index of its heartbeat.  None for those that have finished.
+    beaten: Vec<Option<bool>>,
+}
+
+impl Watchdog {
+    fn from_env() -> Option<Self> {
+        if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+            if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+                return None;
+            }
+        }
+        let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        // Ping at half the timeout, as recommended by sd_watchdog_enabled(3)
+        let interval = Duration::from_micros(usec) / 2;
+        log::info!("Pinging the systemd watchdog every {:?}", interval);
+        Some(Self {
+            interval,
+            state: Mutex::new(WatchdogState {
+                last_ping: Instant::now(),
+                beaten: vec![],
+            }),
+        })
+    }
+}
+
+/// Tells the watchdog that an event loop is making progress.  The
+/// watchdog is only pinged once every event loop has beaten since
+/// the last ping, so that one stuck loop can't be hidden by the others.
+pub struct Heartbeat {
+    watchdog: &'static Watchdog,
+    index: usize,
+    last_beat: Instant,
+}
+
+impl Heartbeat {
+    /// Returns a heartbeat for another event loop, if the watchdog
+    /// was configured by `WatchdogSec=`
+    pub fn from_env() -> Option<Self> {
+        let watchdog = WATCHDOG.get_or_init(Watchdog::from_env).as_ref()?;
+        let mut state = watchdog.state.lock().unwrap();
+        state.beaten.push(Some(false));
+        Some(Self {
+            watchdog,
+            index: state.beaten.len() - 1,
+            last_beat: Instant::now(),
+        })
+    }
+
+    /// The instant by which the event loop should beat again.  Beating
+    /// at half the ping interval means that, once a ping is due, every
+    /// loop has beaten well before the timeout.
+    pub fn next_beat(&self) -> Instant {
+        self.last_beat + self.watchdog.interval / 2
+    }
+
+    pub fn beat_if_due(&mut self) {
+        let now = Instant::now();
+        if now < self.next_beat() {
+            return;
+        }
+        self.last_beat = now;
+
+        let mut state = self.watchdog.state.lock().unwrap();
+        state.beaten[self.index] = Some(true);
+        if now >= state.last_ping + self.watchdog.interval
+            && state.beaten.iter().all(|beaten| *beaten != Some(false))
+        {
+            notify("WATCHDOG=1");
+            state.last_ping = now;
+            for beaten in state.beaten.iter_mut().flatten() {
+                *beaten = false;
+            }
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // A loop that has finished no longer holds up the pings
+        self.watchdog.state.lock().unwrap().beaten[self.index] = None;
+    }
+}